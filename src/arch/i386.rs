@@ -0,0 +1,211 @@
+use nix::libc::user_regs_struct;
+
+use super::{Arch, Regs, Sysno};
+
+/*
+ * Payload
+ * call eax
+ * int3
+ */
+const CALL_STUB : [u8;3] = [0xff, 0xd0, 0xcc];
+
+const INT3 : [u8;1] = [0xcc];
+
+/* int 0x80 */
+const INT80 : [u8;2] = [0xcd, 0x80];
+
+/*
+ * Register set of an i386 thread ('struct user_regs_struct' of
+ * 32-bit Linux). A 64-bit tracer always gets the 64-bit layout
+ * from PTRACE_GETREGS, with the 32-bit registers zero-extended
+ */
+#[allow(non_camel_case_types)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct user_regs_struct_i386 {
+    pub ebx : u32,
+    pub ecx : u32,
+    pub edx : u32,
+    pub esi : u32,
+    pub edi : u32,
+    pub ebp : u32,
+    pub eax : u32,
+    pub xds : u32,
+    pub xes : u32,
+    pub xfs : u32,
+    pub xgs : u32,
+    pub orig_eax : u32,
+    pub eip : u32,
+    pub xcs : u32,
+    pub eflags : u32,
+    pub esp : u32,
+    pub xss : u32
+}
+
+impl From<&user_regs_struct> for user_regs_struct_i386 {
+    fn from(regs : &user_regs_struct) -> Self {
+        return user_regs_struct_i386 {
+            ebx: regs.rbx as u32,
+            ecx: regs.rcx as u32,
+            edx: regs.rdx as u32,
+            esi: regs.rsi as u32,
+            edi: regs.rdi as u32,
+            ebp: regs.rbp as u32,
+            eax: regs.rax as u32,
+            xds: regs.ds as u32,
+            xes: regs.es as u32,
+            xfs: regs.fs as u32,
+            xgs: regs.gs as u32,
+            orig_eax: regs.orig_rax as u32,
+            eip: regs.rip as u32,
+            xcs: regs.cs as u32,
+            eflags: regs.eflags as u32,
+            esp: regs.rsp as u32,
+            xss: regs.ss as u32
+        };
+    }
+}
+
+impl user_regs_struct_i386 {
+    /*
+     * Writes the 32-bit registers back into the 64-bit layout
+     * used by ptrace. 'orig_eax' is sign-extended, as -1 means
+     * that the thread is not in a system call
+     */
+    pub fn store(&self, regs : &mut user_regs_struct) {
+        regs.rbx = u64::from(self.ebx);
+        regs.rcx = u64::from(self.ecx);
+        regs.rdx = u64::from(self.edx);
+        regs.rsi = u64::from(self.esi);
+        regs.rdi = u64::from(self.edi);
+        regs.rbp = u64::from(self.ebp);
+        regs.rax = u64::from(self.eax);
+        regs.ds = u64::from(self.xds);
+        regs.es = u64::from(self.xes);
+        regs.fs = u64::from(self.xfs);
+        regs.gs = u64::from(self.xgs);
+        regs.orig_rax = self.orig_eax as i32 as i64 as u64;
+        regs.rip = u64::from(self.eip);
+        regs.cs = u64::from(self.xcs);
+        regs.eflags = u64::from(self.eflags);
+        regs.rsp = u64::from(self.esp);
+        regs.ss = u64::from(self.xss);
+    }
+}
+
+/*
+ * i386 System V ABI (cdecl): every argument goes on the stack
+ * and the caller cleans it up. Runs as a compat process under
+ * a 64-bit kernel
+ */
+pub struct I386;
+
+impl Arch for I386 {
+    fn name(&self) -> &'static str {
+        return "i386";
+    }
+
+    fn word_size(&self) -> u64 {
+        return 4;
+    }
+
+    fn stack_align(&self) -> u64 {
+        return 16;
+    }
+
+    fn red_zone(&self) -> u64 {
+        return 0;
+    }
+
+    fn sp(&self, regs : &Regs) -> u64 {
+        return u64::from(user_regs_struct_i386::from(regs).esp);
+    }
+
+    fn set_sp(&self, regs : &mut Regs, sp : u64) {
+        let mut regs32 = user_regs_struct_i386::from(&*regs);
+        regs32.esp = sp as u32;
+        regs32.store(regs);
+    }
+
+    fn ip(&self, regs : &Regs) -> u64 {
+        return u64::from(user_regs_struct_i386::from(regs).eip);
+    }
+
+    fn set_ip(&self, regs : &mut Regs, ip : u64) {
+        let mut regs32 = user_regs_struct_i386::from(&*regs);
+        regs32.eip = ip as u32;
+        regs32.store(regs);
+    }
+
+    fn reg_args(&self) -> usize {
+        return 0;
+    }
+
+    fn set_arg(&self, _regs : &mut Regs, _index : usize, _value : u64) {
+    }
+
+    fn retval(&self, regs : &Regs) -> u64 {
+        return u64::from(user_regs_struct_i386::from(regs).eax);
+    }
+
+    fn syscall_nr(&self, regs : &Regs) -> Option<u64> {
+        return match user_regs_struct_i386::from(regs).orig_eax as i32 {
+            nr if nr >= 0 => Some(nr as u64),
+            _ => None
+        };
+    }
+
+    fn clear_syscall(&self, regs : &mut Regs) {
+        let mut regs32 = user_regs_struct_i386::from(&*regs);
+        regs32.orig_eax = -1_i32 as u32;
+        regs32.store(regs);
+    }
+
+    fn call_stub(&self) -> &'static [u8] {
+        return &CALL_STUB;
+    }
+
+    fn set_call_target(&self, regs : &mut Regs, addr : u64) {
+        let mut regs32 = user_regs_struct_i386::from(&*regs);
+        regs32.eax = addr as u32;
+        regs32.store(regs);
+    }
+
+    fn syscall_insn(&self) -> &'static [u8] {
+        return &INT80;
+    }
+
+    fn syscall_number(&self, sysno : Sysno) -> u64 {
+        return match sysno {
+            Sysno::Mmap => 192,
+            Sysno::Munmap => 91,
+            Sysno::Mprotect => 125,
+            Sysno::MemfdCreate => 356,
+            Sysno::Write => 4,
+            Sysno::Close => 6
+        };
+    }
+
+    /* mmap2 takes the offset in 4096 byte units */
+    fn mmap_offset(&self, offset : u64) -> u64 {
+        return offset >> 12;
+    }
+
+    /* eax, then ebx, ecx, edx, esi, edi and ebp */
+    fn set_syscall(&self, regs : &mut Regs, nr : u64, args : &[u64]) {
+        let mut regs32 = user_regs_struct_i386::from(&*regs);
+        regs32.eax = nr as u32;
+        let arg_regs = [&mut regs32.ebx, &mut regs32.ecx, &mut regs32.edx, &mut regs32.esi, &mut regs32.edi, &mut regs32.ebp];
+        for (reg, value) in arg_regs.into_iter().zip(args) {
+            *reg = *value as u32;
+        }
+        regs32.store(regs);
+    }
+
+    fn breakpoint(&self) -> &'static [u8] {
+        return &INT3;
+    }
+
+    fn format_regs(&self, regs : &Regs) -> String {
+        return format!("{:x?}", user_regs_struct_i386::from(regs));
+    }
+}
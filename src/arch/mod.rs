@@ -0,0 +1,165 @@
+pub mod x86_64;
+pub mod i386;
+
+use nix::libc::user_regs_struct;
+
+use crate::elf::elfdefs::{ELFCLASS32, ELFCLASS64, EM_386, EM_X86_64};
+use crate::error::{Error, Result};
+
+/*
+ * Register set of a thread, as returned by PTRACE_GETREGS
+ * to this (64-bit) tracer
+ */
+pub type Regs = user_regs_struct;
+
+/*
+ * Kernel-internal results of an interrupted system call that
+ * asks to be restarted when the thread returns to user mode
+ */
+pub const ERESTARTSYS : i64 = 512;
+pub const ERESTARTNOINTR : i64 = 513;
+pub const ERESTARTNOHAND : i64 = 514;
+pub const ERESTART_RESTARTBLOCK : i64 = 516;
+
+/*
+ * System call a thread was stopped in
+ */
+#[derive(Debug, Clone, Copy)]
+pub struct Syscall {
+    pub nr : u64,
+    pub retval : i64
+}
+
+impl Syscall {
+    /*
+     * Whether the kernel will restart the system call (rewinding
+     * the instruction pointer to it) once the thread resumes with
+     * these registers
+     */
+    pub fn restarts(&self) -> bool {
+        return matches!(-self.retval, ERESTARTSYS | ERESTARTNOINTR | ERESTARTNOHAND | ERESTART_RESTARTBLOCK);
+    }
+}
+
+/*
+ * System calls issued in the target
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Sysno {
+    /* 'mmap2' on i386, which takes the offset in pages */
+    Mmap,
+    Munmap,
+    Mprotect,
+    MemfdCreate,
+    Write,
+    Close
+}
+
+impl Sysno {
+    pub fn name(&self) -> &'static str {
+        return match self {
+            Sysno::Mmap => "mmap",
+            Sysno::Munmap => "munmap",
+            Sysno::Mprotect => "mprotect",
+            Sysno::MemfdCreate => "memfd_create",
+            Sysno::Write => "write",
+            Sysno::Close => "close"
+        };
+    }
+}
+
+/*
+ * Everything an injection technique needs to know about the
+ * target architecture. Techniques are written once against
+ * this trait, and a port only adds a backend
+ */
+pub trait Arch {
+    fn name(&self) -> &'static str;
+
+    /* Size of a pointer/stack slot, in bytes */
+    fn word_size(&self) -> u64;
+
+    /* Mask of the valid bits of an address */
+    fn addr_mask(&self) -> u64 {
+        return match self.word_size() {
+            8 => u64::MAX,
+            size => (1 << (size * 8)) - 1
+        };
+    }
+
+    /* Alignment of the stack pointer at a call instruction */
+    fn stack_align(&self) -> u64;
+
+    /* Bytes below the stack pointer that leaf functions may use */
+    fn red_zone(&self) -> u64;
+
+    fn sp(&self, regs : &Regs) -> u64;
+    fn set_sp(&self, regs : &mut Regs, sp : u64);
+    fn ip(&self, regs : &Regs) -> u64;
+    fn set_ip(&self, regs : &mut Regs, ip : u64);
+
+    /* Number of integer/pointer arguments passed in registers */
+    fn reg_args(&self) -> usize;
+    fn set_arg(&self, regs : &mut Regs, index : usize, value : u64);
+
+    /* Integer/pointer return value of a function */
+    fn retval(&self, regs : &Regs) -> u64;
+
+    /* Number of the system call the thread is in ('orig_rax'), if any */
+    fn syscall_nr(&self, regs : &Regs) -> Option<u64>;
+
+    /*
+     * Marks the thread as not being in a system call, so that the
+     * kernel does not apply restart semantics to these registers
+     */
+    fn clear_syscall(&self, regs : &mut Regs);
+
+    fn interrupted_syscall(&self, regs : &Regs) -> Option<Syscall> {
+        let nr = self.syscall_nr(regs)?;
+        let shift = 64 - self.word_size() * 8;
+        let retval = ((self.retval(regs) << shift) as i64) >> shift; // sign extend
+        return Some(Syscall { nr, retval });
+    }
+
+    /*
+     * Code that calls the function whose address was set with
+     * 'set_call_target' and then traps into the tracer
+     */
+    fn call_stub(&self) -> &'static [u8];
+    fn set_call_target(&self, regs : &mut Regs, addr : u64);
+
+    /* Instruction that enters the kernel */
+    fn syscall_insn(&self) -> &'static [u8];
+    fn syscall_number(&self, sysno : Sysno) -> u64;
+
+    /* Offset argument of 'Sysno::Mmap' for an offset in bytes */
+    fn mmap_offset(&self, offset : u64) -> u64 {
+        return offset;
+    }
+
+    /* Prepares the registers for 'syscall_insn' */
+    fn set_syscall(&self, regs : &mut Regs, nr : u64, args : &[u64]);
+
+    /* Software breakpoint instruction */
+    fn breakpoint(&self) -> &'static [u8];
+
+    /* Encodes a value as a stack slot */
+    fn word_bytes(&self, value : u64) -> Vec<u8> {
+        return value.to_le_bytes()[..self.word_size() as usize].to_vec();
+    }
+
+    /* Formats the registers in the view of the target */
+    fn format_regs(&self, regs : &Regs) -> String;
+}
+
+/*
+ * Selects the backend for the 'e_machine' and class
+ * of the target executable
+ */
+pub fn from_elf(machine : u16, class : u8) -> Result<Box<dyn Arch>> {
+    return match (machine, class) {
+        (EM_X86_64, ELFCLASS64) => Ok(Box::new(x86_64::X86_64)),
+        (EM_386, ELFCLASS32) => Ok(Box::new(i386::I386)),
+        _ => Err(Error::elf("Unsupported target architecture"))
+    };
+}
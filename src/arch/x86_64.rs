@@ -0,0 +1,125 @@
+use super::{Arch, Regs, Sysno};
+
+/*
+ * Payload
+ * call rax
+ * int3
+ */
+const CALL_STUB : [u8;3] = [0xff, 0xd0, 0xcc];
+
+const INT3 : [u8;1] = [0xcc];
+
+/* syscall */
+const SYSCALL : [u8;2] = [0x0f, 0x05];
+
+/*
+ * x86_64 System V ABI: the first 6 integer arguments go in
+ * rdi, rsi, rdx, rcx, r8 and r9, and the rest on the stack
+ */
+pub struct X86_64;
+
+impl Arch for X86_64 {
+    fn name(&self) -> &'static str {
+        return "x86_64";
+    }
+
+    fn word_size(&self) -> u64 {
+        return 8;
+    }
+
+    fn stack_align(&self) -> u64 {
+        return 16;
+    }
+
+    fn red_zone(&self) -> u64 {
+        return 128;
+    }
+
+    fn sp(&self, regs : &Regs) -> u64 {
+        return regs.rsp;
+    }
+
+    fn set_sp(&self, regs : &mut Regs, sp : u64) {
+        regs.rsp = sp;
+    }
+
+    fn ip(&self, regs : &Regs) -> u64 {
+        return regs.rip;
+    }
+
+    fn set_ip(&self, regs : &mut Regs, ip : u64) {
+        regs.rip = ip;
+    }
+
+    fn reg_args(&self) -> usize {
+        return 6;
+    }
+
+    fn set_arg(&self, regs : &mut Regs, index : usize, value : u64) {
+        let reg = match index {
+            0 => &mut regs.rdi,
+            1 => &mut regs.rsi,
+            2 => &mut regs.rdx,
+            3 => &mut regs.rcx,
+            4 => &mut regs.r8,
+            5 => &mut regs.r9,
+            _ => return
+        };
+        *reg = value;
+    }
+
+    fn retval(&self, regs : &Regs) -> u64 {
+        return regs.rax;
+    }
+
+    fn syscall_nr(&self, regs : &Regs) -> Option<u64> {
+        return match regs.orig_rax as i64 {
+            nr if nr >= 0 => Some(nr as u64),
+            _ => None
+        };
+    }
+
+    fn clear_syscall(&self, regs : &mut Regs) {
+        regs.orig_rax = -1_i64 as u64;
+    }
+
+    fn call_stub(&self) -> &'static [u8] {
+        return &CALL_STUB;
+    }
+
+    fn set_call_target(&self, regs : &mut Regs, addr : u64) {
+        regs.rax = addr;
+    }
+
+    fn syscall_insn(&self) -> &'static [u8] {
+        return &SYSCALL;
+    }
+
+    fn syscall_number(&self, sysno : Sysno) -> u64 {
+        return match sysno {
+            Sysno::Mmap => 9,
+            Sysno::Munmap => 11,
+            Sysno::Mprotect => 10,
+            Sysno::MemfdCreate => 319,
+            Sysno::Write => 1,
+            Sysno::Close => 3
+        };
+    }
+
+    /* rax, then rdi, rsi, rdx, r10, r8 and r9 */
+    fn set_syscall(&self, regs : &mut Regs, nr : u64, args : &[u64]) {
+        regs.rax = nr;
+        let arg_regs = [&mut regs.rdi, &mut regs.rsi, &mut regs.rdx, &mut regs.r10, &mut regs.r8, &mut regs.r9];
+        for (reg, value) in arg_regs.into_iter().zip(args) {
+            *reg = *value;
+        }
+    }
+
+    fn breakpoint(&self) -> &'static [u8] {
+        return &INT3;
+    }
+
+    fn format_regs(&self, regs : &Regs) -> String {
+        return format!("{:x?}", regs);
+    }
+}
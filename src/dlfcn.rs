@@ -0,0 +1,375 @@
+use std::fmt;
+use std::fs::File;
+use std::os::unix::fs::FileExt;
+use std::time::Instant;
+use regex::Regex;
+
+use crate::proc::{enum_maps, open_module, root_path, wait_for, MapsEntry, Module};
+use crate::elf::{enum_relocations, enum_symbols, read_ehdr, vaddr_to_offset, Relocation, Symbol};
+use crate::elf::elfdefs::{ElfW_Ehdr, ElfW, Elf32_Ehdr, Elf64_Ehdr, ELFCLASS32, ET_EXEC, R_386_GLOB_DAT, R_X86_64_GLOB_DAT};
+use crate::error::{Error, Result};
+use crate::remote::{RemoteProcess, RemoteArg};
+use crate::injector::RTLD_LAZY;
+
+/* Longest dlerror() message read from the target */
+const DLERROR_MAX : usize = 4096;
+
+/* libdl of glibc < 2.34, which provides the dlerror missing from '__libc_dlopen_mode' */
+const LIBDL : &str = "libdl.so.2";
+
+/*
+ * Internal flag that makes '__libc_dlopen_mode' behave like the
+ * public dlopen (the library gets a dlopen reference, so it can
+ * be unloaded with dlclose later)
+ */
+pub const __RTLD_DLOPEN : i32 = 0x80000000_u32 as i32;
+
+/*
+ * C library implementations that can be targeted
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LibcFlavour {
+    Glibc,
+    /* 'ld-musl-ARCH.so.1' is both the dynamic loader and the libc */
+    Musl
+}
+
+/*
+ * Where the dlopen implementation used for the target comes from
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DlopenSource {
+    /* 'dlopen' exported by libc.so.6 (glibc 2.34+) */
+    LibcDlopen,
+    /* '__libc_dlopen_mode' exported by libc.so.6 (glibc < 2.34) */
+    LibcDlopenMode,
+    /* 'dlopen' exported by libdl.so.2 (glibc < 2.34) */
+    LibdlDlopen,
+    /* 'dlopen' exported by ld-musl */
+    MuslDlopen
+}
+
+/*
+ * The dynamic loading functions resolved in the target
+ */
+#[derive(Debug, Clone)]
+pub struct DlApi {
+    pub flavour : LibcFlavour,
+    pub source : DlopenSource,
+    pub module : MapsEntry,
+    pub dlopen : u64,
+    pub dlclose : u64,
+    /* Not available for '__libc_dlopen_mode', which discards errors */
+    pub dlerror : Option<u64>,
+    /* 'dlsym', or '__libc_dlsym' along with '__libc_dlopen_mode' */
+    pub dlsym : Option<u64>
+}
+
+impl LibcFlavour {
+    /*
+     * Tells which C library a mapped 'libc' module belongs to
+     */
+    pub fn of(libc : &MapsEntry) -> Self {
+        let filename = libc.path.rsplit('/').next().unwrap_or("");
+        if filename.starts_with("ld-musl-") || filename.contains("musl") {
+            return LibcFlavour::Musl;
+        }
+
+        return LibcFlavour::Glibc;
+    }
+}
+
+impl fmt::Display for LibcFlavour {
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            LibcFlavour::Glibc => "glibc",
+            LibcFlavour::Musl => "musl"
+        };
+        return write!(f, "{}", name);
+    }
+}
+
+impl fmt::Display for DlopenSource {
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            DlopenSource::LibcDlopen => "dlopen (libc)",
+            DlopenSource::LibcDlopenMode => "__libc_dlopen_mode (libc)",
+            DlopenSource::LibdlDlopen => "dlopen (libdl)",
+            DlopenSource::MuslDlopen => "dlopen (ld-musl)"
+        };
+        return write!(f, "{}", name);
+    }
+}
+
+impl DlApi {
+    /*
+     * Resolves dlopen/dlclose in the target. On glibc this tries, in
+     * order: the public dlopen in libc, __libc_dlopen_mode in libc,
+     * and the public dlopen in libdl. On musl, the loader exports
+     * dlopen itself
+     */
+    pub fn resolve(pid : i32, libc : &MapsEntry, verbose : bool) -> Result<Self> {
+        let mut last_error : Option<Error> = None;
+
+        let flavour = LibcFlavour::of(libc);
+        log!(verbose, "Libc flavour: {}", flavour);
+        let candidates : &[(DlopenSource, &str, &str, &str)] = match flavour {
+            LibcFlavour::Glibc => &[
+                (DlopenSource::LibcDlopen, "dlopen", "dlclose", "dlsym"),
+                (DlopenSource::LibcDlopenMode, "__libc_dlopen_mode", "__libc_dlclose", "__libc_dlsym"),
+                (DlopenSource::LibdlDlopen, "dlopen", "dlclose", "dlsym")
+            ],
+            LibcFlavour::Musl => &[
+                (DlopenSource::MuslDlopen, "dlopen", "dlclose", "dlsym")
+            ]
+        };
+
+        for &(source, dlopen_name, dlclose_name, dlsym_name) in candidates {
+            let module = match source {
+                DlopenSource::LibdlDlopen => match find_module(pid, r".*/libdl[.\-].*")? {
+                    Some(m) => m,
+                    None => continue // libdl is only loaded by programs that link it
+                },
+                _ => libc.clone()
+            };
+
+            let dlopen = find_symbol(pid, &module, &[dlopen_name], verbose);
+            let dlclose = find_symbol(pid, &module, &[dlclose_name], verbose);
+            match (dlopen, dlclose) {
+                (Ok(dlopen), Ok(dlclose)) => {
+                    let dlerror = match source {
+                        DlopenSource::LibcDlopenMode => None,
+                        _ => find_symbol(pid, &module, &["dlerror"], verbose).ok()
+                    };
+                    let dlsym = find_symbol(pid, &module, &[dlsym_name], verbose).ok();
+                    log!(verbose, "Using {} from {}", source, module.path);
+                    return Ok(DlApi { flavour, source, module, dlopen, dlclose, dlerror, dlsym });
+                },
+                (Err(e), _) | (_, Err(e)) => last_error = Some(e)
+            }
+        }
+
+        return Err(last_error.unwrap_or(Error::LibcNotFound(pid)));
+    }
+
+    /*
+     * With '__libc_dlopen_mode', which discards error messages, loads
+     * 'libdl.so.2' through it and switches to libdl's dlopen, so that
+     * dlerror is available. Keeps '__libc_dlopen_mode' if libdl can't
+     * be loaded
+     */
+    pub fn load_libdl(&mut self, remote : &mut RemoteProcess, verbose : bool) -> Result<()> {
+        if self.source != DlopenSource::LibcDlopenMode {
+            return Ok(());
+        }
+
+        let mode = self.mode(RTLD_LAZY) as u32 as u64;
+        if remote.call(self.dlopen, &[RemoteArg::Str(LIBDL), RemoteArg::Int(mode)])? == 0 {
+            log!(verbose, "Unable to load {}, keeping {}", LIBDL, self.source);
+            return Ok(());
+        }
+
+        let pid = remote.pid();
+        let module = match find_module(pid, r".*/libdl[.\-].*")? {
+            Some(m) => m,
+            None => return Ok(())
+        };
+        let dlopen = find_symbol(pid, &module, &["dlopen"], verbose)?;
+        let dlclose = find_symbol(pid, &module, &["dlclose"], verbose)?;
+        self.dlerror = find_symbol(pid, &module, &["dlerror"], verbose).ok();
+        self.dlsym = find_symbol(pid, &module, &["dlsym"], verbose).ok();
+        self.source = DlopenSource::LibdlDlopen;
+        self.dlopen = dlopen;
+        self.dlclose = dlclose;
+        log!(verbose, "Using {} from {}", self.source, module.path);
+        self.module = module;
+
+        return Ok(());
+    }
+
+    /*
+     * Retrieves the dlerror() message of the thread that made the
+     * last failing call, if there is one
+     */
+    pub fn error(&self, remote : &mut RemoteProcess) -> Result<Option<String>> {
+        let dlerror = match self.dlerror {
+            Some(addr) => addr,
+            None => return Ok(None)
+        };
+
+        let message = remote.call(dlerror, &[])?;
+        if message == 0 {
+            return Ok(None);
+        }
+
+        return Ok(Some(remote.memory().read_cstr(message, DLERROR_MAX)?));
+    }
+
+    /*
+     * Resolves 'symbol' in the library loaded as 'handle' with the
+     * dlsym of the target or, without one, in the '.dynsym' of the
+     * library mapped as 'module' ('path' is the library for errors)
+     */
+    pub fn symbol(&self, remote : &mut RemoteProcess, handle : u64, module : &Module, symbol : &str, path : &str, verbose : bool) -> Result<u64> {
+        let not_found = || Error::SymbolNotFound { symbol: String::from(symbol), path: String::from(path) };
+        let dlsym = match self.dlsym {
+            Some(addr) => addr,
+            None => {
+                let file = open_module(remote.pid(), module)?;
+                return find_symbol_in(&file, module.base(), &[symbol], verbose)
+                    .map_err(|e| e.in_file(path))?
+                    .ok_or_else(not_found);
+            }
+        };
+
+        return match remote.call(dlsym, &[RemoteArg::Ptr(handle), RemoteArg::Str(symbol)])? {
+            0 => Err(not_found()),
+            addr => Ok(addr)
+        };
+    }
+
+    /*
+     * Adapts the RTLD_* flags to the dlopen variant in use
+     */
+    pub fn mode(&self, mode : i32) -> i32 {
+        return match self.source {
+            DlopenSource::LibcDlopenMode => mode | __RTLD_DLOPEN,
+            _ => mode
+        };
+    }
+}
+
+/*
+ * Finds the first mapping of a module whose path matches 'pattern'
+ */
+pub fn find_module(pid : i32, pattern : &str) -> Result<Option<MapsEntry>> {
+    let mut module : Option<MapsEntry> = None;
+    let re = Regex::new(pattern)?;
+    enum_maps(pid, |entry : MapsEntry| {
+        if !re.is_match(entry.path.as_str()) {
+            return true;
+        }
+
+        module = Some(entry);
+        return false;
+    })?;
+
+    return Ok(module);
+}
+
+/*
+ * Finds the C library of the process: 'libc.so.6'/'libc-2.x.so' for
+ * glibc, or the musl loader ('ld-musl-ARCH.so.1', also reachable as
+ * 'libc.musl-ARCH.so.1')
+ */
+pub fn find_libc(pid : i32) -> Result<MapsEntry> {
+    return find_module(pid, r".*/(libc[.\-].*|ld-musl-.*\.so.*)")?.ok_or(Error::LibcNotFound(pid));
+}
+
+/*
+ * Waits until the C library is mapped in the process, as is
+ * the case shortly after it is started. The dynamic loader maps
+ * it before relocating it, see 'libc_relocated'
+ */
+pub fn wait_libc(pid : i32, deadline : Option<Instant>) -> Result<MapsEntry> {
+    return wait_for(&format!("libc to be loaded in process {}", pid), deadline, || match find_libc(pid) {
+        Ok(libc) => Ok(Some(libc)),
+        Err(Error::LibcNotFound(_)) => Ok(None),
+        Err(e) => Err(e)
+    });
+}
+
+/*
+ * Tells whether the dynamic loader has relocated the C library of
+ * the process yet, by looking for a GOT slot filled by a GLOB_DAT
+ * relocation: until then, the slots hold their content in the file
+ * (0, or the implicit addend with REL relocations)
+ */
+pub fn libc_relocated(remote : &RemoteProcess, libc : &MapsEntry) -> Result<bool> {
+    let path = root_path(remote.pid(), &libc.path);
+    let file = File::open(&path).map_err(|e| Error::io(&path, e))?;
+    let bias = if read_ehdr(&file).map_err(|e| e.in_file(&path))?.get_type() == ET_EXEC { 0 } else { libc.base };
+    let glob_dat = if remote.elf_class() == ELFCLASS32 { R_386_GLOB_DAT } else { R_X86_64_GLOB_DAT };
+
+    let mut slots : Vec<u64> = vec![];
+    enum_relocations(&file, |relocation : &Relocation| {
+        if relocation.r_type == glob_dat {
+            slots.push(relocation.offset);
+        }
+        return true;
+    }).map_err(|e| e.in_file(&path))?;
+
+    let word_size = remote.arch().word_size() as usize;
+    for slot in slots {
+        let mut original = vec![0u8; word_size];
+        if let Some(offset) = vaddr_to_offset(&file, slot) {
+            file.read_exact_at(&mut original, offset).map_err(|e| Error::io(&path, e))?;
+        }
+
+        if remote.read_memory(bias + slot, word_size)? != original {
+            return Ok(true);
+        }
+    }
+
+    return Ok(false);
+}
+
+/*
+ * Resolves the absolute address of the first symbol of 'names'
+ * (in order of preference) in the module loaded at 'module.base'
+ * of the process 'pid', reading the module through the root of
+ * the process. Only definitions with the default symbol version
+ * are considered
+ */
+pub fn find_symbol(pid : i32, module : &MapsEntry, names : &[&str], verbose : bool) -> Result<u64> {
+    let path = root_path(pid, &module.path);
+    let file = File::open(&path).map_err(|e| Error::io(&path, e))?;
+
+    return find_symbol_in(&file, module.base, names, verbose)
+        .map_err(|e| e.in_file(&path))?
+        .ok_or_else(|| Error::SymbolNotFound { symbol: names.join(" / "), path: module.path.clone() });
+}
+
+/*
+ * Looks up the first symbol of 'names' in the ELF 'file', loaded at
+ * 'base' (ignored for ET_EXEC files, whose addresses are absolute)
+ */
+fn find_symbol_in(file : &File, base : u64, names : &[&str], verbose : bool) -> Result<Option<u64>> {
+    let mut found : Vec<Option<(u64, Option<String>)>> = vec![None; names.len()];
+    enum_symbols(file, |ehdr : &ElfW<Elf32_Ehdr, Elf64_Ehdr>, symbol : &Symbol| -> bool {
+        // Imports from other modules are listed as undefined symbols,
+        // and hidden versions are only kept for old binaries
+        if !symbol.is_defined() || symbol.hidden {
+            return true;
+        }
+
+        let index = match names.iter().position(|name| *name == symbol.name) {
+            Some(i) => i,
+            None => return true
+        };
+
+        if found[index].is_none() {
+            let mut value = symbol.value;
+            if ehdr.get_type() != ET_EXEC {
+                value += base; // calculate absolute address
+            }
+            found[index] = Some((value, symbol.version.clone()));
+        }
+
+        return index != 0;
+    })?;
+
+    for (name, found) in names.iter().zip(found) {
+        if let Some((addr, version)) = found {
+            log!(verbose, "Symbol info: ");
+            log!(verbose, "\tSymbol: {}", name);
+            if let Some(version) = version {
+                log!(verbose, "\tVersion: {}", version);
+            }
+            log!(verbose, "\tAddress: {:#x}", addr);
+            return Ok(Some(addr));
+        }
+    }
+
+    return Ok(None);
+}
@@ -28,7 +28,7 @@ pub type Elf64_Section = u16;
 
 pub const EI_NIDENT : usize = 16;
 
-#[derive(Debug)]
+#[derive(Debug, Default, Clone, Copy)]
 #[repr(C)]
 pub struct Elf32_Ehdr {
     pub e_ident : [u8; EI_NIDENT],
@@ -47,7 +47,7 @@ pub struct Elf32_Ehdr {
     pub e_shstrndx : Elf32_Half
 }
 
-#[derive(Debug)]
+#[derive(Debug, Default, Clone, Copy)]
 #[repr(C)]
 pub struct Elf64_Ehdr {
     pub e_ident : [u8; EI_NIDENT],
@@ -67,7 +67,7 @@ pub struct Elf64_Ehdr {
 }
 
 pub const EI_MAG0 : usize = 0;
-pub const ELFMAG0 : i8 = 0x7f as i8;
+pub const ELFMAG0 : i8 = 0x7f;
 
 pub const EI_MAG1 : usize = 1;
 pub const ELFMAG1 : i8 = 'E' as i8;
@@ -94,7 +94,11 @@ pub const ET_DYN : u64 = 3;
 pub const ET_CORE : u64 = 4;
 pub const ET_NUM : u64 = 5;
 
-#[derive(Debug)]
+pub const EM_NONE : u16 = 0;
+pub const EM_386 : u16 = 3;
+pub const EM_X86_64 : u16 = 62;
+
+#[derive(Debug, Default, Clone, Copy)]
 #[repr(C)]
 pub struct Elf32_Shdr {
     pub sh_name : Elf32_Word,
@@ -109,7 +113,7 @@ pub struct Elf32_Shdr {
     pub sh_entsize : Elf32_Word
 }
 
-#[derive(Debug)]
+#[derive(Debug, Default, Clone, Copy)]
 #[repr(C)]
 pub struct Elf64_Shdr {
     pub sh_name : Elf64_Word,
@@ -124,7 +128,7 @@ pub struct Elf64_Shdr {
     pub sh_entsize : Elf64_Xword,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Default, Clone, Copy)]
 #[repr(C)]
 pub struct Elf32_Sym {
     pub st_name : Elf32_Word,
@@ -135,7 +139,7 @@ pub struct Elf32_Sym {
     pub st_shndx : Elf32_Section,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Default, Clone, Copy)]
 #[repr(C)]
 pub struct Elf64_Sym {
     pub st_name : Elf64_Word,
@@ -146,6 +150,200 @@ pub struct Elf64_Sym {
     pub st_size : Elf64_Xword
 }
 
+#[derive(Debug, Default, Clone, Copy)]
+#[repr(C)]
+pub struct Elf32_Phdr {
+    pub p_type : Elf32_Word,
+    pub p_offset : Elf32_Off,
+    pub p_vaddr : Elf32_Addr,
+    pub p_paddr : Elf32_Addr,
+    pub p_filesz : Elf32_Word,
+    pub p_memsz : Elf32_Word,
+    pub p_flags : Elf32_Word,
+    pub p_align : Elf32_Word
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+#[repr(C)]
+pub struct Elf64_Phdr {
+    pub p_type : Elf64_Word,
+    pub p_flags : Elf64_Word,
+    pub p_offset : Elf64_Off,
+    pub p_vaddr : Elf64_Addr,
+    pub p_paddr : Elf64_Addr,
+    pub p_filesz : Elf64_Xword,
+    pub p_memsz : Elf64_Xword,
+    pub p_align : Elf64_Xword
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+#[repr(C)]
+pub struct Elf32_Dyn {
+    pub d_tag : Elf32_Sword,
+    pub d_val : Elf32_Word
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+#[repr(C)]
+pub struct Elf64_Dyn {
+    pub d_tag : Elf64_Sxword,
+    pub d_val : Elf64_Xword
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+#[repr(C)]
+pub struct Elf32_Rel {
+    pub r_offset : Elf32_Addr,
+    pub r_info : Elf32_Word
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+#[repr(C)]
+pub struct Elf64_Rel {
+    pub r_offset : Elf64_Addr,
+    pub r_info : Elf64_Xword
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+#[repr(C)]
+pub struct Elf32_Rela {
+    pub r_offset : Elf32_Addr,
+    pub r_info : Elf32_Word,
+    pub r_addend : Elf32_Sword
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+#[repr(C)]
+pub struct Elf64_Rela {
+    pub r_offset : Elf64_Addr,
+    pub r_info : Elf64_Xword,
+    pub r_addend : Elf64_Sxword
+}
+
+pub const SHN_UNDEF : u16 = 0;
+
+pub const SHT_NULL : u32 = 0;
+pub const SHT_PROGBITS : u32 = 1;
+pub const SHT_SYMTAB : u32 = 2;
+pub const SHT_STRTAB : u32 = 3;
+pub const SHT_RELA : u32 = 4;
+pub const SHT_HASH : u32 = 5;
+pub const SHT_DYNAMIC : u32 = 6;
+pub const SHT_NOTE : u32 = 7;
+pub const SHT_NOBITS : u32 = 8;
+pub const SHT_REL : u32 = 9;
+pub const SHT_DYNSYM : u32 = 11;
+
+pub const PT_NULL : u32 = 0;
+pub const PT_LOAD : u32 = 1;
+pub const PT_DYNAMIC : u32 = 2;
+pub const PT_INTERP : u32 = 3;
+pub const PT_NOTE : u32 = 4;
+pub const PT_PHDR : u32 = 6;
+pub const PT_TLS : u32 = 7;
+pub const PT_GNU_EH_FRAME : u32 = 0x6474e550;
+pub const PT_GNU_STACK : u32 = 0x6474e551;
+pub const PT_GNU_RELRO : u32 = 0x6474e552;
+
+pub const NT_GNU_BUILD_ID : u32 = 3;
+
+pub const AT_NULL : u64 = 0;
+pub const AT_PHDR : u64 = 3;
+pub const AT_PHENT : u64 = 4;
+pub const AT_PHNUM : u64 = 5;
+pub const AT_PAGESZ : u64 = 6;
+pub const AT_BASE : u64 = 7;
+pub const AT_ENTRY : u64 = 9;
+
+pub const PF_X : u32 = 1;
+pub const PF_W : u32 = 2;
+pub const PF_R : u32 = 4;
+
+pub const DT_NULL : i64 = 0;
+pub const DT_NEEDED : i64 = 1;
+pub const DT_PLTRELSZ : i64 = 2;
+pub const DT_PLTGOT : i64 = 3;
+pub const DT_HASH : i64 = 4;
+pub const DT_STRTAB : i64 = 5;
+pub const DT_SYMTAB : i64 = 6;
+pub const DT_RELA : i64 = 7;
+pub const DT_RELASZ : i64 = 8;
+pub const DT_RELAENT : i64 = 9;
+pub const DT_STRSZ : i64 = 10;
+pub const DT_SYMENT : i64 = 11;
+pub const DT_INIT : i64 = 12;
+pub const DT_FINI : i64 = 13;
+pub const DT_SONAME : i64 = 14;
+pub const DT_REL : i64 = 17;
+pub const DT_RELSZ : i64 = 18;
+pub const DT_RELENT : i64 = 19;
+pub const DT_PLTREL : i64 = 20;
+pub const DT_JMPREL : i64 = 23;
+pub const DT_INIT_ARRAY : i64 = 25;
+pub const DT_FINI_ARRAY : i64 = 26;
+pub const DT_INIT_ARRAYSZ : i64 = 27;
+pub const DT_FINI_ARRAYSZ : i64 = 28;
+pub const DT_GNU_HASH : i64 = 0x6ffffef5;
+pub const DT_VERSYM : i64 = 0x6ffffff0;
+pub const DT_VERDEF : i64 = 0x6ffffffc;
+pub const DT_VERDEFNUM : i64 = 0x6ffffffd;
+
+pub const R_X86_64_NONE : u32 = 0;
+pub const R_X86_64_64 : u32 = 1;
+pub const R_X86_64_PC32 : u32 = 2;
+pub const R_X86_64_COPY : u32 = 5;
+pub const R_X86_64_GLOB_DAT : u32 = 6;
+pub const R_X86_64_JUMP_SLOT : u32 = 7;
+pub const R_X86_64_RELATIVE : u32 = 8;
+pub const R_X86_64_DTPMOD64 : u32 = 16;
+pub const R_X86_64_DTPOFF64 : u32 = 17;
+pub const R_X86_64_TPOFF64 : u32 = 18;
+pub const R_X86_64_IRELATIVE : u32 = 37;
+
+pub const R_386_GLOB_DAT : u32 = 6;
+
+/*
+ * Symbol versioning (identical for both classes)
+ */
+#[derive(Debug, Default, Clone, Copy)]
+#[repr(C)]
+pub struct Elf64_Verdef {
+    pub vd_version : Elf64_Half,
+    pub vd_flags : Elf64_Half,
+    pub vd_ndx : Elf64_Half,
+    pub vd_cnt : Elf64_Half,
+    pub vd_hash : Elf64_Word,
+    pub vd_aux : Elf64_Word,
+    pub vd_next : Elf64_Word
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+#[repr(C)]
+pub struct Elf64_Verdaux {
+    pub vda_name : Elf64_Word,
+    pub vda_next : Elf64_Word
+}
+
+pub type Elf32_Verdef = Elf64_Verdef;
+pub type Elf32_Verdaux = Elf64_Verdaux;
+
+pub const VER_NDX_LOCAL : u16 = 0;
+pub const VER_NDX_GLOBAL : u16 = 1;
+pub const VERSYM_HIDDEN : u16 = 0x8000;
+pub const VERSYM_VERSION : u16 = 0x7fff;
+
+pub const STB_LOCAL : u8 = 0;
+pub const STB_GLOBAL : u8 = 1;
+pub const STB_WEAK : u8 = 2;
+
+pub const STT_NOTYPE : u8 = 0;
+pub const STT_OBJECT : u8 = 1;
+pub const STT_FUNC : u8 = 2;
+pub const STT_SECTION : u8 = 3;
+pub const STT_FILE : u8 = 4;
+pub const STT_TLS : u8 = 6;
+pub const STT_GNU_IFUNC : u8 = 10;
+
 /********************/
 
 impl Elf32_Shdr {
@@ -204,7 +402,7 @@ impl Elf32_Ehdr {
 }
 
 impl Elf64_Ehdr {
-    fn new() -> Self {
+    pub fn new() -> Self {
         return Elf64_Ehdr {
             e_ident: [0; EI_NIDENT],
             e_type: 0,
@@ -227,13 +425,16 @@ impl Elf64_Ehdr {
 use std::mem::{size_of, transmute};
 use std::os::unix::fs::FileExt;
 use std::fs::File;
-use std::io::{Seek, SeekFrom, BufRead, BufReader};
+use super::{read_cstr, read_struct, Section, Segment};
 
 pub trait ElfW_Ehdr {
     fn get_class(&self) -> u8;
     fn get_magic(&self) -> Vec<u8>;
     fn get_type(&self) -> u64;
-    fn enum_sections<T>(&self, file : &File, callback : T) -> Option<()> where T : FnMut(String, u64, u64, u64) -> bool;
+    fn get_machine(&self) -> u16;
+    fn get_entry(&self) -> u64;
+    fn enum_sections<T>(&self, file : &File, callback : T) -> Option<()> where T : FnMut(&Section) -> bool;
+    fn enum_segments<T>(&self, file : &File, callback : T) -> Option<()> where T : FnMut(&Segment) -> bool;
 }
 
 impl ElfW_Ehdr for Elf32_Ehdr {
@@ -249,32 +450,67 @@ impl ElfW_Ehdr for Elf32_Ehdr {
         return self.e_type as u64;
     }
 
-    fn enum_sections<T>(&self, file : &File, mut callback : T) -> Option<()> where T : FnMut(String, u64, u64, u64) -> bool {
+    fn get_machine(&self) -> u16 {
+        return self.e_machine;
+    }
+
+    fn get_entry(&self) -> u64 {
+        return u64::from(self.e_entry);
+    }
+
+    fn enum_sections<T>(&self, file : &File, mut callback : T) -> Option<()> where T : FnMut(&Section) -> bool {
+        // The section headers are optional
+        if self.e_shoff == 0 || self.e_shnum == 0 {
+            return Some(());
+        }
+
         // Get shstrtab
-        let mut shstrtab_off = self.e_shoff + (self.e_shstrndx * self.e_shentsize) as Elf32_Off;
+        let shstrtab_hdr_off = u64::from(self.e_shoff) + u64::from(self.e_shstrndx) * u64::from(self.e_shentsize);
         let mut shbuf : [u8;size_of::<Elf32_Shdr>()] = [0;size_of::<Elf32_Shdr>()];
-        file.read_exact_at(&mut shbuf, shstrtab_off as u64).ok()?;
+        file.read_exact_at(&mut shbuf, shstrtab_hdr_off).ok()?;
         let shstrtab = unsafe {
             transmute::<[u8;size_of::<Elf32_Shdr>()], Elf32_Shdr>(shbuf)
         };
 
-        shstrtab_off = shstrtab.sh_offset;
+        let shstrtab_off = u64::from(shstrtab.sh_offset);
 
         // Loop through sections
         for i in 0..self.e_shnum {
-            file.read_exact_at(&mut shbuf, (self.e_shoff + (i * self.e_shentsize) as Elf32_Off) as u64).ok()?;
+            file.read_exact_at(&mut shbuf, u64::from(self.e_shoff) + u64::from(i) * u64::from(self.e_shentsize)).ok()?;
             let shdr = unsafe {
                 transmute::<[u8;size_of::<Elf32_Shdr>()], Elf32_Shdr>(shbuf)
             };
 
-            let mut section_name_buf : Vec<u8> = vec![];
-            let mut reader = BufReader::new(file);
-            reader.seek(SeekFrom::Start((shstrtab_off + shdr.sh_name) as u64));
-            reader.read_until(b'\x00', &mut section_name_buf);
-            section_name_buf.pop(); // remove null terminator
+            let section = Section {
+                index: i as usize,
+                name: read_cstr(file, shstrtab_off + u64::from(shdr.sh_name))?,
+                sh_type: shdr.sh_type,
+                flags: u64::from(shdr.sh_flags),
+                addr: u64::from(shdr.sh_addr),
+                offset: u64::from(shdr.sh_offset),
+                size: u64::from(shdr.sh_size),
+                entsize: u64::from(shdr.sh_entsize),
+                link: shdr.sh_link
+            };
+            if !callback(&section) {
+                break;
+            }
+        }
+        return Some(());
+    }
 
-            let section_name = String::from_utf8_lossy(&section_name_buf).to_string();
-            if !callback(section_name, shdr.sh_offset as u64, shdr.sh_entsize as u64, shdr.sh_size as u64) {
+    fn enum_segments<T>(&self, file : &File, mut callback : T) -> Option<()> where T : FnMut(&Segment) -> bool {
+        for i in 0..self.e_phnum {
+            let phdr : Elf32_Phdr = read_struct(file, u64::from(self.e_phoff) + u64::from(i) * u64::from(self.e_phentsize))?;
+            let segment = Segment {
+                p_type: phdr.p_type,
+                flags: phdr.p_flags,
+                offset: u64::from(phdr.p_offset),
+                vaddr: u64::from(phdr.p_vaddr),
+                filesz: u64::from(phdr.p_filesz),
+                memsz: u64::from(phdr.p_memsz)
+            };
+            if !callback(&segment) {
                 break;
             }
         }
@@ -295,32 +531,67 @@ impl ElfW_Ehdr for Elf64_Ehdr {
         return self.e_type as u64;
     }
 
-    fn enum_sections<T>(&self, file : &File, mut callback : T) -> Option<()> where T : FnMut(String, u64, u64, u64) -> bool {
+    fn get_machine(&self) -> u16 {
+        return self.e_machine;
+    }
+
+    fn get_entry(&self) -> u64 {
+        return self.e_entry;
+    }
+
+    fn enum_sections<T>(&self, file : &File, mut callback : T) -> Option<()> where T : FnMut(&Section) -> bool {
+        // The section headers are optional
+        if self.e_shoff == 0 || self.e_shnum == 0 {
+            return Some(());
+        }
+
         // Get shstrtab
-        let mut shstrtab_off = self.e_shoff + (self.e_shstrndx * self.e_shentsize) as Elf64_Off;
+        let shstrtab_hdr_off = self.e_shoff + u64::from(self.e_shstrndx) * u64::from(self.e_shentsize);
         let mut shbuf : [u8;size_of::<Elf64_Shdr>()] = [0;size_of::<Elf64_Shdr>()];
-        file.read_exact_at(&mut shbuf, shstrtab_off as u64).ok()?;
+        file.read_exact_at(&mut shbuf, shstrtab_hdr_off).ok()?;
         let shstrtab = unsafe {
             transmute::<[u8;size_of::<Elf64_Shdr>()], Elf64_Shdr>(shbuf)
         };
 
-        shstrtab_off = shstrtab.sh_offset;
+        let shstrtab_off = shstrtab.sh_offset;
 
         // Loop through sections
         for i in 0..self.e_shnum {
-            file.read_exact_at(&mut shbuf, (self.e_shoff + (i * self.e_shentsize) as Elf64_Off) as u64).ok()?;
+            file.read_exact_at(&mut shbuf, self.e_shoff + u64::from(i) * u64::from(self.e_shentsize)).ok()?;
             let shdr = unsafe {
                 transmute::<[u8;size_of::<Elf64_Shdr>()], Elf64_Shdr>(shbuf)
             };
 
-            let mut section_name_buf : Vec<u8> = vec![];
-            let mut reader = BufReader::new(file);
-            reader.seek(SeekFrom::Start((shstrtab_off + shdr.sh_name as Elf64_Off) as u64));
-            reader.read_until(b'\x00', &mut section_name_buf);
-            section_name_buf.pop(); // remove null terminator
+            let section = Section {
+                index: i as usize,
+                name: read_cstr(file, shstrtab_off + u64::from(shdr.sh_name))?,
+                sh_type: shdr.sh_type,
+                flags: shdr.sh_flags,
+                addr: shdr.sh_addr,
+                offset: shdr.sh_offset,
+                size: shdr.sh_size,
+                entsize: shdr.sh_entsize,
+                link: shdr.sh_link
+            };
+            if !callback(&section) {
+                break;
+            }
+        }
+        return Some(());
+    }
 
-            let section_name = String::from_utf8_lossy(&section_name_buf).to_string();
-            if !callback(section_name, shdr.sh_offset as u64, shdr.sh_entsize as u64, shdr.sh_size as u64) {
+    fn enum_segments<T>(&self, file : &File, mut callback : T) -> Option<()> where T : FnMut(&Segment) -> bool {
+        for i in 0..self.e_phnum {
+            let phdr : Elf64_Phdr = read_struct(file, self.e_phoff + u64::from(i) * u64::from(self.e_phentsize))?;
+            let segment = Segment {
+                p_type: phdr.p_type,
+                flags: phdr.p_flags,
+                offset: phdr.p_offset,
+                vaddr: phdr.p_vaddr,
+                filesz: phdr.p_filesz,
+                memsz: phdr.p_memsz
+            };
+            if !callback(&segment) {
                 break;
             }
         }
@@ -334,8 +605,7 @@ pub enum ElfW<A, B> {
     Elf64(B)
 }
 
-extern crate lib;
-use lib::elfw;
+use rust_injector_macros::elfw;
 
 impl<A, B> ElfW_Ehdr for ElfW<A, B>
 where A : ElfW_Ehdr, B : ElfW_Ehdr {
@@ -351,7 +621,19 @@ where A : ElfW_Ehdr, B : ElfW_Ehdr {
         return elfw!(e.get_type());
     }
 
-    fn enum_sections<T>(&self, file : &File, mut callback : T) -> Option<()> where T : FnMut(String, u64, u64, u64) -> bool {
+    fn get_machine(&self) -> u16 {
+        return elfw!(e.get_machine());
+    }
+
+    fn get_entry(&self) -> u64 {
+        return elfw!(e.get_entry());
+    }
+
+    fn enum_sections<T>(&self, file : &File, callback : T) -> Option<()> where T : FnMut(&Section) -> bool {
         return elfw!(e.enum_sections(file, callback));
     }
+
+    fn enum_segments<T>(&self, file : &File, callback : T) -> Option<()> where T : FnMut(&Segment) -> bool {
+        return elfw!(e.enum_segments(file, callback));
+    }
 }
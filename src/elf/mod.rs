@@ -2,38 +2,124 @@ use std::os::unix::fs::FileExt;
 use std::fs::File;
 use std::mem::{size_of, transmute};
 use std::io::{Seek, SeekFrom, BufRead, BufReader};
+use std::collections::HashMap;
 
 pub mod elfdefs;
 use elfdefs::*;
+use crate::error::{Error, Result};
 
-macro_rules! separator {
-    () => {
-        println!("====================");
+/*
+ * Class independent views of the section headers,
+ * program headers and symbols of an ELF file
+ */
+#[derive(Debug, Clone)]
+pub struct Section {
+    pub index : usize,
+    pub name : String,
+    pub sh_type : u32,
+    pub flags : u64,
+    pub addr : u64,
+    pub offset : u64,
+    pub size : u64,
+    pub entsize : u64,
+    pub link : u32
+}
+
+#[derive(Debug, Clone)]
+pub struct Segment {
+    pub p_type : u32,
+    pub flags : u32,
+    pub offset : u64,
+    pub vaddr : u64,
+    pub filesz : u64,
+    pub memsz : u64
+}
+
+#[derive(Debug, Clone)]
+pub struct Symbol {
+    pub name : String,
+    pub value : u64,
+    pub size : u64,
+    pub info : u8,
+    pub shndx : u16,
+    pub table : SymbolTable,
+    /* Version name from '.gnu.version_d' (dynamic symbols only) */
+    pub version : Option<String>,
+    /* Non-default version ('sym@VER' instead of 'sym@@VER') */
+    pub hidden : bool
+}
+
+impl Symbol {
+    pub fn is_defined(&self) -> bool {
+        return self.shndx != SHN_UNDEF;
+    }
+
+    pub fn bind(&self) -> u8 {
+        return self.info >> 4;
     }
+
+    pub fn sym_type(&self) -> u8 {
+        return self.info & 0xf;
+    }
+}
+
+/*
+ * Dynamic relocation. The addend of REL relocations (i386) is the
+ * implicit one, stored in the file at the relocated address
+ */
+#[derive(Debug, Clone)]
+pub struct Relocation {
+    pub offset : u64,
+    pub sym : u32,
+    pub r_type : u32,
+    pub addend : i64
+}
+
+/*
+ * Symbol tables that can be searched. The static '.symtab' is
+ * usually stripped from system libraries, while the dynamic
+ * '.dynsym' is always present in shared objects
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SymbolTable {
+    Symtab,
+    Dynsym
+}
+
+/* Location of a symbol table and its string table in the file */
+struct TableLocation {
+    table : SymbolTable,
+    sym_off : u64,
+    sym_count : u64,
+    entsize : u64,
+    str_off : u64
+}
+
+/* Symbol version information of '.dynsym' */
+struct Versions {
+    versym_off : u64,
+    names : HashMap<u16, String>
 }
 
-pub fn read_ehdr(file : &File) -> Result<ElfW<Elf32_Ehdr, Elf64_Ehdr>, &'static str> {
+pub fn read_ehdr(file : &File) -> Result<ElfW<Elf32_Ehdr, Elf64_Ehdr>> {
     let mut magic : [u8;SELFMAG] = [0;SELFMAG];
-    match file.read_exact_at(&mut magic, 0) {
-        Err(_) => return Err("Unable to read ELF file"),
-        _ => {  }
+    if file.read_exact_at(&mut magic, 0).is_err() {
+        return Err(Error::elf("Unable to read ELF file"));
     }
 
     if magic != ELFMAG {
-        return Err("The file is not ELF");
+        return Err(Error::elf("The file is not ELF"));
     }
 
     let mut class : [u8;1] = [ELFCLASSNONE];
-    match file.read_exact_at(&mut class, EI_CLASS as u64) {
-        Err(_) => return Err("Unable to read ELF class"),
-        _ => {  }
+    if file.read_exact_at(&mut class, EI_CLASS as u64).is_err() {
+        return Err(Error::elf("Unable to read ELF class"));
     }
 
     if class[0] == ELFCLASS32 {
         let mut ehdrbuf : [u8;size_of::<Elf32_Ehdr>()] = [0;size_of::<Elf32_Ehdr>()];
-        match file.read_exact_at(&mut ehdrbuf, 0) {
-            Err(_) => return Err("Unable to read ELF header"),
-            _ => {  }
+        if file.read_exact_at(&mut ehdrbuf, 0).is_err() {
+            return Err(Error::elf("Unable to read ELF header"));
         }
 
         let ehdr = unsafe {
@@ -42,9 +128,8 @@ pub fn read_ehdr(file : &File) -> Result<ElfW<Elf32_Ehdr, Elf64_Ehdr>, &'static
         return Ok(ElfW::Elf32(ehdr));
     } else if class[0] == ELFCLASS64 {
         let mut ehdrbuf : [u8;size_of::<Elf64_Ehdr>()] = [0;size_of::<Elf64_Ehdr>()];
-        match file.read_exact_at(&mut ehdrbuf, 0) {
-            Err(_) => return Err("Unable to read ELF header"),
-            _ => {  }
+        if file.read_exact_at(&mut ehdrbuf, 0).is_err() {
+            return Err(Error::elf("Unable to read ELF header"));
         }
 
         let ehdr = unsafe {
@@ -52,107 +137,495 @@ pub fn read_ehdr(file : &File) -> Result<ElfW<Elf32_Ehdr, Elf64_Ehdr>, &'static
         };
         return Ok(ElfW::Elf64(ehdr));
     } else {
-        return Err("Invalid ELF class");
+        return Err(Error::elf("Invalid ELF class"));
     }
 }
 
-pub fn enum_symbols<F>(file : &File, mut callback : F) -> Result<(), &'static str>
-where F : FnMut(&ElfW<Elf32_Ehdr, Elf64_Ehdr>, String, u64) -> bool {
-    let ehdr = match read_ehdr(file) {
-        Ok(hdr) => hdr,
-        Err(e) => return Err(e)
-    };
+/*
+ * Reads a null terminated string from 'file' at 'offset'
+ */
+pub(crate) fn read_cstr(file : &File, offset : u64) -> Option<String> {
+    let mut strbuf : Vec<u8> = vec![];
+    let mut reader = BufReader::new(file);
+    reader.seek(SeekFrom::Start(offset)).ok()?;
+    reader.read_until(b'\x00', &mut strbuf).ok()?;
+    strbuf.pop(); // remove null terminator
 
-    let mut strtab_off : Option<u64> = None;
-    let mut symtab_off : Option<u64> = None;
-    let mut symtab_entsize : u64 = 0;
-    let mut symtab_size : u64 = 0;
+    return Some(String::from_utf8_lossy(&strbuf).to_string());
+}
 
-    ehdr.enum_sections(file, |name : String, offset : u64, entsize : u64, size : u64| -> bool {
-        if strtab_off != None && symtab_off != None {
+/*
+ * Reads one of the plain #[repr(C)] structures from elfdefs
+ * (or an integer) from 'file' at 'offset'
+ */
+pub(crate) fn read_struct<T : Copy + Default>(file : &File, offset : u64) -> Option<T> {
+    let mut buf = vec![0;size_of::<T>()];
+    file.read_exact_at(&mut buf, offset).ok()?;
+    return Some(unsafe { std::ptr::read_unaligned(buf.as_ptr() as *const T) });
+}
+
+/*
+ * Converts a virtual address into a file offset
+ * using the PT_LOAD segments
+ */
+pub fn vaddr_to_offset(file : &File, vaddr : u64) -> Option<u64> {
+    let ehdr = read_ehdr(file).ok()?;
+    let mut offset = None;
+    ehdr.enum_segments(file, |segment : &Segment| -> bool {
+        if segment.p_type == PT_LOAD && vaddr >= segment.vaddr && vaddr < segment.vaddr + segment.filesz {
+            offset = Some(vaddr - segment.vaddr + segment.offset);
             return false;
         }
 
-        if name == ".symtab" {
-            println!("Symbol Table: ");
-            println!("\tName: {}", name);
-            println!("\tOffset: {:#x}", offset);
-            println!("\tEntsize: {:#x}", entsize);
-            println!("\tSize: {:#x}", size);
-            separator!();
+        return true;
+    })?;
+
+    return offset;
+}
+
+/*
+ * Reads the GNU build ID note (NT_GNU_BUILD_ID) from the PT_NOTE
+ * segments, if the file has one
+ */
+pub fn read_build_id(file : &File) -> Option<Vec<u8>> {
+    let ehdr = read_ehdr(file).ok()?;
+    let mut notes : Vec<Segment> = vec![];
+    ehdr.enum_segments(file, |segment : &Segment| -> bool {
+        if segment.p_type == PT_NOTE {
+            notes.push(segment.clone());
+        }
+        return true;
+    })?;
+
+    let align4 = |value : u64| (value + 3) & !3;
+    for note in notes {
+        let mut offset = note.offset;
+        while offset + 12 <= note.offset + note.filesz {
+            let namesz = read_struct::<u32>(file, offset)? as u64;
+            let descsz = read_struct::<u32>(file, offset + 4)? as u64;
+            let n_type = read_struct::<u32>(file, offset + 8)?;
+            let name_off = offset + 12;
+            let desc_off = name_off + align4(namesz);
+
+            if n_type == NT_GNU_BUILD_ID && read_cstr(file, name_off)? == "GNU" {
+                let mut desc = vec![0;descsz as usize];
+                file.read_exact_at(&mut desc, desc_off).ok()?;
+                return Some(desc);
+            }
+            offset = desc_off + align4(descsz);
+        }
+    }
+
+    return None;
+}
 
-            symtab_off = Some(offset);
-            symtab_entsize = entsize;
-            symtab_size = size;
-        } else if name == ".strtab" {
-            println!("String Table: ");
-            println!("\tName: {}", name);
-            println!("\tOffset: {:#x}", offset);
-            println!("\tEntsize: {:#x}", entsize);
-            println!("\tSize: {:#x}", size);
-            separator!();
-            strtab_off = Some(offset);
+/*
+ * Enumerates the entries of the dynamic section (through PT_DYNAMIC,
+ * so it works without section headers) as (tag, value) pairs
+ */
+pub fn enum_dynamic<F>(file : &File, mut callback : F) -> Result<()>
+where F : FnMut(i64, u64) -> bool {
+    let ehdr = read_ehdr(file)?;
+    let mut dynamic : Option<Segment> = None;
+    ehdr.enum_segments(file, |segment : &Segment| -> bool {
+        if segment.p_type == PT_DYNAMIC {
+            dynamic = Some(segment.clone());
+            return false;
         }
 
         return true;
-    });
+    }).ok_or(Error::elf("Unable to read program headers from ELF file"))?;
 
-    let strtab_off = match strtab_off {
-        Some(off) => off,
-        None => return Err("Unable to retrieve string table from ELF file")
-    };
-    let symtab_off = match symtab_off {
-        Some(off) => off,
-        None => return Err("Unable to retrieve symbol table from ELF file")
+    let dynamic = dynamic.ok_or(Error::elf("The ELF file has no dynamic segment"))?;
+    let entsize = match ehdr.get_class() {
+        ELFCLASS32 => size_of::<Elf32_Dyn>() as u64,
+        _ => size_of::<Elf64_Dyn>() as u64
     };
-    let entnum = symtab_size / symtab_entsize;
-    for i in 0..entnum {
-        match ehdr.get_class() {
+
+    for i in 0..(dynamic.filesz / entsize) {
+        let offset = dynamic.offset + i * entsize;
+        let (tag, value) = match ehdr.get_class() {
             ELFCLASS32 => {
-                let mut symbuf : [u8;size_of::<Elf32_Sym>()] = [0;size_of::<Elf32_Sym>()];
-                file.read_exact_at(&mut symbuf, symtab_off + i * symtab_entsize);
-                let sym : Elf32_Sym = unsafe {
-                    transmute::<[u8;size_of::<Elf32_Sym>()], Elf32_Sym>(symbuf)
-                };
-
-                let mut symstr_buf : Vec<u8> = vec![];
-                let mut reader = BufReader::new(file);
-                reader.seek(SeekFrom::Start(strtab_off + sym.st_name as u64));
-                reader.read_until(b'\x00', &mut symstr_buf);
-                symstr_buf.pop(); // remove null terminator
-
-                let symstr = String::from_utf8_lossy(&symstr_buf).to_string();
-                let symval = sym.st_value as u64;
-
-                if !callback(&ehdr, symstr, symval) {
-                    break;
+                let dynent : Elf32_Dyn = read_struct(file, offset).ok_or(Error::elf("Unable to read dynamic entry"))?;
+                (dynent.d_tag as i64, dynent.d_val as u64)
+            },
+            _ => {
+                let dynent : Elf64_Dyn = read_struct(file, offset).ok_or(Error::elf("Unable to read dynamic entry"))?;
+                (dynent.d_tag, dynent.d_val)
+            }
+        };
+
+        if tag == DT_NULL || !callback(tag, value) {
+            break;
+        }
+    }
+
+    return Ok(());
+}
+
+/*
+ * Enumerates the dynamic relocations of the file: the DT_RELA and
+ * DT_REL tables, then the PLT relocations (DT_JMPREL), in the format
+ * given by DT_PLTREL
+ */
+pub fn enum_relocations<F>(file : &File, mut callback : F) -> Result<()>
+where F : FnMut(&Relocation) -> bool {
+    let class = read_ehdr(file)?.get_class();
+    let (mut relaent, mut relent) = match class {
+        ELFCLASS32 => (size_of::<Elf32_Rela>() as u64, size_of::<Elf32_Rel>() as u64),
+        _ => (size_of::<Elf64_Rela>() as u64, size_of::<Elf64_Rel>() as u64)
+    };
+    let mut rela : Option<u64> = None;
+    let mut relasz : u64 = 0;
+    let mut rel : Option<u64> = None;
+    let mut relsz : u64 = 0;
+    let mut jmprel : Option<u64> = None;
+    let mut pltrelsz : u64 = 0;
+    let mut pltrel = DT_RELA;
+
+    enum_dynamic(file, |tag : i64, value : u64| -> bool {
+        match tag {
+            DT_RELA => rela = Some(value),
+            DT_RELASZ => relasz = value,
+            DT_RELAENT => relaent = value,
+            DT_REL => rel = Some(value),
+            DT_RELSZ => relsz = value,
+            DT_RELENT => relent = value,
+            DT_JMPREL => jmprel = Some(value),
+            DT_PLTRELSZ => pltrelsz = value,
+            DT_PLTREL => pltrel = value as i64,
+            _ => {  }
+        }
+
+        return true;
+    })?;
+
+    if relaent == 0 || relent == 0 {
+        return Err(Error::elf("Invalid relocation entry size"));
+    }
+
+    let tables = [(rela, relasz, true), (rel, relsz, false), (jmprel, pltrelsz, pltrel != DT_REL)];
+    for (table, size, is_rela) in tables {
+        let table = match table {
+            Some(t) => vaddr_to_offset(file, t).ok_or(Error::elf("Unable to locate relocation table"))?,
+            None => continue
+        };
+
+        let entsize = if is_rela { relaent } else { relent };
+        for i in 0..(size / entsize) {
+            let offset = table + i * entsize;
+            let relocation = match (class, is_rela) {
+                (ELFCLASS32, true) => {
+                    let rel : Elf32_Rela = read_struct(file, offset).ok_or(Error::elf("Unable to read relocation"))?;
+                    Relocation { offset: rel.r_offset as u64, sym: rel.r_info >> 8, r_type: rel.r_info & 0xff, addend: rel.r_addend as i64 }
+                },
+                (ELFCLASS32, false) => {
+                    let rel : Elf32_Rel = read_struct(file, offset).ok_or(Error::elf("Unable to read relocation"))?;
+                    let addend = implicit_addend::<i32>(file, rel.r_offset as u64).unwrap_or(0) as i64;
+                    Relocation { offset: rel.r_offset as u64, sym: rel.r_info >> 8, r_type: rel.r_info & 0xff, addend }
+                },
+                (_, true) => {
+                    let rel : Elf64_Rela = read_struct(file, offset).ok_or(Error::elf("Unable to read relocation"))?;
+                    Relocation { offset: rel.r_offset, sym: (rel.r_info >> 32) as u32, r_type: rel.r_info as u32, addend: rel.r_addend }
+                },
+                (_, false) => {
+                    let rel : Elf64_Rel = read_struct(file, offset).ok_or(Error::elf("Unable to read relocation"))?;
+                    let addend = implicit_addend::<i64>(file, rel.r_offset).unwrap_or(0);
+                    Relocation { offset: rel.r_offset, sym: (rel.r_info >> 32) as u32, r_type: rel.r_info as u32, addend }
                 }
+            };
+
+            if !callback(&relocation) {
+                return Ok(());
             }
+        }
+    }
 
-            ELFCLASS64 => {
-                let mut symbuf : [u8;size_of::<Elf64_Sym>()] = [0;size_of::<Elf64_Sym>()];
-                file.read_exact_at(&mut symbuf, symtab_off + i * symtab_entsize);
-                let sym : Elf64_Sym = unsafe {
-                    transmute::<[u8;size_of::<Elf64_Sym>()], Elf64_Sym>(symbuf)
-                };
+    return Ok(());
+}
 
-                let mut symstr_buf : Vec<u8> = vec![];
-                let mut reader = BufReader::new(file);
-                reader.seek(SeekFrom::Start(strtab_off + sym.st_name as u64));
-                reader.read_until(b'\x00', &mut symstr_buf);
-                symstr_buf.pop(); // remove null terminator
+/*
+ * Addend of a REL relocation, stored in the file at the relocated
+ * address (none for addresses past the file, like '.bss')
+ */
+fn implicit_addend<T : Copy + Default>(file : &File, vaddr : u64) -> Option<T> {
+    return read_struct::<T>(file, vaddr_to_offset(file, vaddr)?);
+}
 
-                let symstr = String::from_utf8_lossy(&symstr_buf).to_string();
-                let symval = sym.st_value as u64;
+/*
+ * Locates '.dynsym' through the dynamic segment. The amount of
+ * symbols is not stored anywhere, so it is taken from the hash
+ * tables (DT_HASH or DT_GNU_HASH)
+ */
+fn locate_dynsym(file : &File, class : u8) -> Option<TableLocation> {
+    let mut symtab : Option<u64> = None;
+    let mut strtab : Option<u64> = None;
+    let mut syment : u64 = match class {
+        ELFCLASS32 => size_of::<Elf32_Sym>() as u64,
+        _ => size_of::<Elf64_Sym>() as u64
+    };
+    let mut hash : Option<u64> = None;
+    let mut gnu_hash : Option<u64> = None;
 
-                if !callback(&ehdr, symstr, symval) {
-                    break;
+    enum_dynamic(file, |tag : i64, value : u64| -> bool {
+        match tag {
+            DT_SYMTAB => symtab = Some(value),
+            DT_STRTAB => strtab = Some(value),
+            DT_SYMENT => syment = value,
+            DT_HASH => hash = Some(value),
+            DT_GNU_HASH => gnu_hash = Some(value),
+            _ => {  }
+        }
+
+        return true;
+    }).ok()?;
+
+    let sym_off = vaddr_to_offset(file, symtab?)?;
+    let str_off = vaddr_to_offset(file, strtab?)?;
+
+    let sym_count = if let Some(hash) = hash {
+        // nbucket, nchain; there is one chain entry per symbol
+        let hash_off = vaddr_to_offset(file, hash)?;
+        read_struct::<u32>(file, hash_off + 4)? as u64
+    } else {
+        gnu_hash_symcount(file, vaddr_to_offset(file, gnu_hash?)?, class)?
+    };
+
+    return Some(TableLocation { table: SymbolTable::Dynsym, sym_off, sym_count, entsize: syment, str_off });
+}
+
+/*
+ * The symbol count of a DT_GNU_HASH table is one past the last
+ * symbol in the chain of the highest bucket
+ */
+fn gnu_hash_symcount(file : &File, offset : u64, class : u8) -> Option<u64> {
+    let nbuckets = read_struct::<u32>(file, offset)? as u64;
+    let symoffset = read_struct::<u32>(file, offset + 4)? as u64;
+    let bloom_size = read_struct::<u32>(file, offset + 8)? as u64;
+    let bloom_entsize : u64 = if class == ELFCLASS32 { 4 } else { 8 };
+
+    let buckets_off = offset + 16 + bloom_size * bloom_entsize;
+    let chains_off = buckets_off + nbuckets * 4;
+
+    let mut last_sym : u64 = 0;
+    for i in 0..nbuckets {
+        last_sym = last_sym.max(read_struct::<u32>(file, buckets_off + i * 4)? as u64);
+    }
+
+    if last_sym < symoffset {
+        return Some(symoffset);
+    }
+
+    loop {
+        let chain = read_struct::<u32>(file, chains_off + (last_sym - symoffset) * 4)?;
+        if chain & 1 != 0 {
+            return Some(last_sym + 1);
+        }
+        last_sym += 1;
+    }
+}
+
+/*
+ * Reads the version index table (DT_VERSYM) and the names of the
+ * versions defined by the file (DT_VERDEF)
+ */
+fn read_versions(file : &File) -> Option<Versions> {
+    let mut versym : Option<u64> = None;
+    let mut verdef : Option<u64> = None;
+    let mut verdefnum : u64 = 0;
+    let mut strtab : Option<u64> = None;
+
+    enum_dynamic(file, |tag : i64, value : u64| -> bool {
+        match tag {
+            DT_VERSYM => versym = Some(value),
+            DT_VERDEF => verdef = Some(value),
+            DT_VERDEFNUM => verdefnum = value,
+            DT_STRTAB => strtab = Some(value),
+            _ => {  }
+        }
+
+        return true;
+    }).ok()?;
+
+    let versym_off = vaddr_to_offset(file, versym?)?;
+    let str_off = vaddr_to_offset(file, strtab?)?;
+    let mut names : HashMap<u16, String> = HashMap::new();
+    if let Some(verdef) = verdef {
+        let mut verdef_off = vaddr_to_offset(file, verdef)?;
+        for _ in 0..verdefnum {
+            let vd : Elf64_Verdef = read_struct(file, verdef_off)?;
+            let vda : Elf64_Verdaux = read_struct(file, verdef_off + vd.vd_aux as u64)?;
+            names.insert(vd.vd_ndx, read_cstr(file, str_off + vda.vda_name as u64)?);
+
+            if vd.vd_next == 0 {
+                break;
+            }
+            verdef_off += vd.vd_next as u64;
+        }
+    }
+
+    return Some(Versions { versym_off, names });
+}
+
+/*
+ * Enumerates the symbols of every available symbol table
+ */
+pub fn enum_symbols<F>(file : &File, callback : F) -> Result<()>
+where F : FnMut(&ElfW<Elf32_Ehdr, Elf64_Ehdr>, &Symbol) -> bool {
+    return enum_symbols_in(file, &[SymbolTable::Symtab, SymbolTable::Dynsym], callback);
+}
+
+/*
+ * Enumerates the symbols of the given tables, in order. The tables
+ * are found by section type, and '.dynsym' is also found through
+ * PT_DYNAMIC when the file has no section headers
+ */
+pub fn enum_symbols_in<F>(file : &File, tables : &[SymbolTable], mut callback : F) -> Result<()>
+where F : FnMut(&ElfW<Elf32_Ehdr, Elf64_Ehdr>, &Symbol) -> bool {
+    let ehdr = read_ehdr(file)?;
+
+    let mut sections : Vec<Section> = vec![];
+    ehdr.enum_sections(file, |section : &Section| -> bool {
+        sections.push(section.clone());
+        return true;
+    }).ok_or(Error::elf("Unable to read section headers from ELF file"))?;
+
+    let mut locations : Vec<TableLocation> = vec![];
+    for table in tables {
+        let sh_type = match table {
+            SymbolTable::Symtab => SHT_SYMTAB,
+            SymbolTable::Dynsym => SHT_DYNSYM
+        };
+
+        let section = sections.iter().find(|s| s.sh_type == sh_type);
+        if let Some(section) = section {
+            let strtab = match sections.get(section.link as usize) {
+                Some(s) => s,
+                None => return Err(Error::elf("Unable to retrieve string table from ELF file"))
+            };
+            if section.entsize == 0 {
+                return Err(Error::elf("Invalid symbol table entry size"));
+            }
+
+            locations.push(TableLocation {
+                table: *table,
+                sym_off: section.offset,
+                sym_count: section.size / section.entsize,
+                entsize: section.entsize,
+                str_off: strtab.offset
+            });
+        } else if *table == SymbolTable::Dynsym {
+            if let Some(location) = locate_dynsym(file, ehdr.get_class()) {
+                locations.push(location);
+            }
+        }
+    }
+
+    if locations.is_empty() {
+        return Err(Error::elf("Unable to retrieve symbol table from ELF file"));
+    }
+
+    let versions = if tables.contains(&SymbolTable::Dynsym) { read_versions(file) } else { None };
+
+    for location in locations {
+        for i in 0..location.sym_count {
+            let offset = location.sym_off + i * location.entsize;
+            let (st_name, value, size, info, shndx) = match ehdr.get_class() {
+                ELFCLASS32 => {
+                    let sym : Elf32_Sym = read_struct(file, offset).ok_or(Error::elf("Unable to read symbol from ELF file"))?;
+                    (sym.st_name, sym.st_value as u64, sym.st_size as u64, sym.st_info, sym.st_shndx)
+                },
+                ELFCLASS64 => {
+                    let sym : Elf64_Sym = read_struct(file, offset).ok_or(Error::elf("Unable to read symbol from ELF file"))?;
+                    (sym.st_name, sym.st_value, sym.st_size, sym.st_info, sym.st_shndx)
+                },
+                _ => break
+            };
+
+            let name = match read_cstr(file, location.str_off + st_name as u64) {
+                Some(s) => s,
+                None => return Err(Error::elf("Unable to read symbol name from ELF file"))
+            };
+
+            let mut version = None;
+            let mut hidden = false;
+            if let (SymbolTable::Dynsym, Some(versions)) = (location.table, &versions) {
+                if let Some(versym) = read_struct::<u16>(file, versions.versym_off + i * 2) {
+                    let index = versym & VERSYM_VERSION;
+                    hidden = versym & VERSYM_HIDDEN != 0;
+                    if index != VER_NDX_LOCAL && index != VER_NDX_GLOBAL {
+                        version = versions.names.get(&index).cloned();
+                    }
                 }
             }
 
-            _ => break
+            let symbol = Symbol { name, value, size, info, shndx, table: location.table, version, hidden };
+            if !callback(&ehdr, &symbol) {
+                return Ok(());
+            }
         }
     }
 
     return Ok(());
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /*
+     * The C library and the dynamic loader mapped in the test process,
+     * which both have a DT_GNU_HASH table
+     */
+    fn mapped_libraries() -> Vec<String> {
+        let re = regex::Regex::new(r".*/(libc[.\-].*|ld-linux.*)").unwrap();
+        let mut paths : Vec<String> = vec![];
+        crate::proc::enum_maps(std::process::id() as i32, |entry : crate::proc::MapsEntry| {
+            if re.is_match(&entry.path) && !paths.contains(&entry.path) {
+                paths.push(entry.path);
+            }
+            return true;
+        }).unwrap();
+        return paths;
+    }
+
+    /*
+     * Amount of symbols in '.dynsym' according to its section header
+     */
+    fn dynsym_section_count(file : &File) -> u64 {
+        let mut count : Option<u64> = None;
+        read_ehdr(file).unwrap().enum_sections(file, |section : &Section| -> bool {
+            if section.sh_type == SHT_DYNSYM {
+                count = Some(section.size / section.entsize);
+                return false;
+            }
+            return true;
+        }).unwrap();
+        return count.unwrap();
+    }
+
+    fn gnu_hash_offset(file : &File) -> u64 {
+        let mut gnu_hash : Option<u64> = None;
+        enum_dynamic(file, |tag : i64, value : u64| -> bool {
+            if tag == DT_GNU_HASH {
+                gnu_hash = Some(value);
+            }
+            return true;
+        }).unwrap();
+        return vaddr_to_offset(file, gnu_hash.unwrap()).unwrap();
+    }
+
+    #[test]
+    fn gnu_hash_symcount_matches_dynsym() {
+        let libraries = mapped_libraries();
+        assert_eq!(libraries.len(), 2, "libc and ld.so not found in the maps: {:?}", libraries);
+        for path in &libraries {
+            let file = File::open(path).unwrap();
+            let class = read_ehdr(&file).unwrap().get_class();
+
+            let expected = dynsym_section_count(&file);
+            assert_eq!(gnu_hash_symcount(&file, gnu_hash_offset(&file), class), Some(expected), "{}", path);
+            assert_eq!(locate_dynsym(&file, class).unwrap().sym_count, expected, "{}", path);
+        }
+    }
+}
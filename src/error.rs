@@ -0,0 +1,200 @@
+use std::fmt;
+use std::io;
+use nix::errno::Errno;
+use nix::sys::signal::Signal;
+use nix::sys::wait::WaitStatus;
+
+/*
+ * Step of the remote operation that was running when a
+ * ptrace/wait call failed
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    Attach,
+    Interrupt,
+    Wait,
+    GetRegs,
+    SetRegs,
+    ReadMemory,
+    WriteMemory,
+    Continue,
+    Detach
+}
+
+#[derive(Debug)]
+pub enum Error {
+    /* Missing or malformed parameters */
+    InvalidArgument(String),
+    /* A file could not be opened or read */
+    Io { path : String, source : io::Error },
+    /* The file is not a valid/supported ELF */
+    Elf { path : String, reason : &'static str },
+    /* The library and the target have different ELF classes */
+    ClassMismatch { library : u8, process : u8 },
+    /* No process matched the given name or filename */
+    ProcessNotFound(String),
+    /* The process does not exist (anymore) */
+    NoSuchProcess(i32),
+    /* No libc mapping was found in the target */
+    LibcNotFound(i32),
+    /* A symbol could not be resolved in a target module */
+    SymbolNotFound { symbol : String, path : String },
+    /* dlopen returned NULL in the target, with the dlerror() text if available */
+    DlopenFailed { pid : i32, path : String, message : Option<String> },
+    /* The library is not loaded in the target */
+    LibraryNotLoaded { pid : i32, path : String },
+    /* The library is still mapped in the target after being unloaded */
+    LibraryStillLoaded { pid : i32, path : String },
+    /* No injection of the library into the target holds a reference to eject */
+    NotInjected { pid : i32, path : String },
+    /* A ptrace or wait call failed */
+    Ptrace { pid : i32, phase : Phase, errno : Errno },
+    /* The memory of the target could not be read or written */
+    Memory { pid : i32, addr : u64, phase : Phase, errno : Errno },
+    /* A system call issued in the target failed */
+    Syscall { pid : i32, name : String, errno : Errno },
+    /* No executable mapping of the target contains a system call instruction */
+    NoSyscallInstruction(i32),
+    /* A single step of the target trapped somewhere else than expected */
+    UnexpectedTrap { pid : i32, ip : u64, expected : u64 },
+    /* The entry point computed from the executable is not the one given by the kernel */
+    EntryMismatch { pid : i32, entry : u64, at_entry : u64 },
+    /* Nothing matched before the deadline of a wait */
+    Timeout(String),
+    /* The target exited or was killed while being traced */
+    TargetExited { pid : i32, status : WaitStatus },
+    /*
+     * A remote call or system call was stopped by a signal instead of
+     * returning, e.g. a fault in the called code. 'addr' is the faulting
+     * address reported by the kernel, 'ip' where the thread stopped
+     */
+    Fault { pid : i32, signal : Signal, addr : u64, ip : u64 },
+    Regex(regex::Error)
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    pub(crate) fn ptrace(pid : i32, phase : Phase, errno : Errno) -> Self {
+        return Error::Ptrace { pid, phase, errno };
+    }
+
+    pub(crate) fn io(path : &str, source : io::Error) -> Self {
+        return Error::Io { path: String::from(path), source };
+    }
+
+    /*
+     * ELF error of a file that is not known yet: the functions of the
+     * 'elf' module only get a 'File', the caller fills in the path
+     * with 'in_file'
+     */
+    pub(crate) fn elf(reason : &'static str) -> Self {
+        return Error::Elf { path: String::new(), reason };
+    }
+
+    /*
+     * Sets the path of an ELF error that has none yet
+     */
+    pub(crate) fn in_file(self, path : &str) -> Self {
+        return match self {
+            Error::Elf { path: p, reason } if p.is_empty() => Error::Elf { path: String::from(path), reason },
+            e => e
+        };
+    }
+
+    /* Process exit code used by the CLI for this kind of error */
+    pub fn exit_code(&self) -> i32 {
+        return match self {
+            Error::InvalidArgument(_) | Error::Regex(_) => 2,
+            Error::Io { .. } => 3,
+            Error::Elf { .. } | Error::ClassMismatch { .. } => 4,
+            Error::ProcessNotFound(_) | Error::NoSuchProcess(_) => 5,
+            Error::LibcNotFound(_) | Error::SymbolNotFound { .. } => 6,
+            Error::Ptrace { phase: Phase::Attach, .. } => 7,
+            Error::Ptrace { .. } | Error::Memory { .. } | Error::Syscall { .. } => 8,
+            Error::NoSyscallInstruction(_) | Error::UnexpectedTrap { .. } | Error::EntryMismatch { .. } => 8,
+            Error::TargetExited { .. } => 9,
+            Error::LibraryNotLoaded { .. } | Error::LibraryStillLoaded { .. } | Error::NotInjected { .. } => 10,
+            Error::DlopenFailed { .. } => 11,
+            Error::Timeout(_) => 12,
+            Error::Fault { .. } => 13
+        };
+    }
+}
+
+impl fmt::Display for Phase {
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Phase::Attach => "attach",
+            Phase::Interrupt => "interrupt",
+            Phase::Wait => "wait",
+            Phase::GetRegs => "get registers",
+            Phase::SetRegs => "set registers",
+            Phase::ReadMemory => "read memory",
+            Phase::WriteMemory => "write memory",
+            Phase::Continue => "continue",
+            Phase::Detach => "detach"
+        };
+        return write!(f, "{}", name);
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        return match self {
+            Error::InvalidArgument(msg) => write!(f, "Invalid argument: {}", msg),
+            Error::Io { path, source } => write!(f, "Unable to access \"{}\": {}", path, source),
+            Error::Elf { path, reason } if path.is_empty() => write!(f, "Invalid ELF file: {}", reason),
+            Error::Elf { path, reason } => write!(f, "Invalid ELF file \"{}\": {}", path, reason),
+            Error::ClassMismatch { library, process } => write!(
+                f, "The ELF classes from the library ({}) and the process ({}) don't match. Make sure they are the same architecture!",
+                library, process
+            ),
+            Error::ProcessNotFound(name) => write!(f, "No process matches: {}", name),
+            Error::NoSuchProcess(pid) => write!(f, "The process {} does not exist", pid),
+            Error::LibcNotFound(pid) => write!(f, "Could not find libc in process {}", pid),
+            Error::SymbolNotFound { symbol, path } => write!(f, "The symbol {} was not found in {}", symbol, path),
+            Error::DlopenFailed { pid, path, message } => write!(
+                f, "Unable to load {} in process {}: {}",
+                path, pid, message.as_deref().unwrap_or("dlopen returned NULL (no error message available)")
+            ),
+            Error::LibraryNotLoaded { pid, path } => write!(f, "The library {} is not loaded in process {}", path, pid),
+            Error::LibraryStillLoaded { pid, path } => write!(f, "The library {} is still mapped in process {}", path, pid),
+            Error::NotInjected { pid, path } => write!(f, "The library {} was not injected into process {}", path, pid),
+            Error::Ptrace { pid, phase, errno } => write!(f, "Unable to {} (process {}): {}", phase, pid, errno),
+            Error::Memory { pid, addr, phase, errno } => write!(f, "Unable to {} at {:#x} (process {}): {}", phase, addr, pid, errno),
+            Error::Syscall { pid, name, errno } => write!(f, "The system call {} failed in process {}: {}", name, pid, errno),
+            Error::NoSyscallInstruction(pid) => write!(f, "No system call instruction found in process {}", pid),
+            Error::UnexpectedTrap { pid, ip, expected } => write!(
+                f, "The process {} trapped at {:#x} instead of {:#x}", pid, ip, expected
+            ),
+            Error::EntryMismatch { pid, entry, at_entry } => write!(
+                f, "The entry point of process {} ({:#x}) does not match AT_ENTRY ({:#x})", pid, entry, at_entry
+            ),
+            Error::Timeout(what) => write!(f, "Timed out waiting for {}", what),
+            Error::TargetExited { pid, status } => write!(f, "The process {} stopped being traceable: {:?}", pid, status),
+            Error::Fault { pid, signal, addr, ip } => write!(
+                f, "The remote call in process {} was stopped by {} at {:#x} (fault address: {:#x})",
+                pid, signal, ip, addr
+            ),
+            Error::Regex(e) => write!(f, "Invalid regular expression: {}", e)
+        };
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        return match self {
+            Error::Io { source, .. } => Some(source),
+            Error::Ptrace { errno, .. } | Error::Memory { errno, .. } | Error::Syscall { errno, .. } => Some(errno),
+            Error::Regex(e) => Some(e),
+            _ => None
+        };
+    }
+}
+
+impl From<regex::Error> for Error {
+    fn from(e : regex::Error) -> Self {
+        return Error::Regex(e);
+    }
+}
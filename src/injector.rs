@@ -1,18 +1,14 @@
-use regex::Regex;
-use crate::proc::{enum_maps, MapsEntry};
-use crate::elf::{enum_symbols};
-use crate::elf::elfdefs::{ElfW_Ehdr, ElfW, Elf32_Ehdr, Elf64_Ehdr, ET_EXEC, ELFCLASS64, ELFCLASS32};
-use std::fs::File;
-use std::mem::{size_of, transmute};
-use core::ffi::c_void;
-use nix::libc::c_long;
-
-use nix::unistd::Pid;
-use nix::sys::ptrace;
-use nix::sys::ptrace::AddressType;
-use nix::sys::wait;
-use nix::sys::signal::Signal;
-use nix::errno::Errno;
+use crate::proc::{enum_modules, module_file_id, open_exe, read_auxv, root_path, shares_root, start_time, MapsEntry, Module};
+use crate::elf::{read_ehdr, Segment};
+use crate::elf::elfdefs::{ElfW_Ehdr, AT_BASE, AT_ENTRY, AT_PHDR, PT_PHDR};
+use crate::error::{Error, Result};
+use crate::remote::{RemoteProcess, RemoteArg};
+use crate::dlfcn::{DlApi, LibcFlavour, find_libc, libc_relocated};
+use crate::mapper::{self, Mapping};
+use std::fs::{self, File, OpenOptions, canonicalize};
+use std::io::{self, Write};
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
 
 pub const RTLD_LAZY : i32 = 0x1;
 pub const RTLD_NOW : i32 = 0x2;
@@ -23,319 +19,636 @@ pub const RTLD_GLOBAL : i32 = 0x100;
 pub const RTLD_LOCAL : i32 = 0;
 pub const RTLD_NODELETE : i32 = 0x1000;
 
-macro_rules! separator {
-    () => {
-        println!("====================");
+/* Directory (in the target's root) where libraries are copied to */
+const COPY_DIR : &str = "/tmp";
+
+/* Directory (in the temporary directory) where injections record their references */
+const REFS_DIR : &str = "rust-injector";
+
+/*
+ * Builder for a single injection. The target PID and the
+ * library path are required, everything else has defaults
+ */
+#[derive(Debug, Clone)]
+pub struct Injector {
+    pid : Option<i32>,
+    libpath : Option<String>,
+    mode : i32,
+    all_threads : bool,
+    memfd : bool,
+    copy : bool,
+    entry : Option<String>,
+    entry_arg : Option<String>,
+    verbose : bool
+}
+
+/*
+ * Result of a successful injection
+ */
+#[derive(Debug)]
+pub struct Injection {
+    pub pid : i32,
+    pub libpath : String,
+    pub elf_class : u8,
+    pub libc : MapsEntry,
+    pub dl : DlApi,
+    /*
+     * Path passed to dlopen, as seen by the target: the library path,
+     * its copy in the target's root, or '/proc/self/fd/N' in memfd mode
+     */
+    pub dlopen_path : String,
+    pub handle : u64,
+    /* Mappings of the library in the target, found after loading it */
+    pub module : Module,
+    /* Return value of the entry function, if one was called */
+    pub entry_result : Option<u64>
+}
+
+/*
+ * Result of a successful ejection
+ */
+#[derive(Debug)]
+pub struct Ejection {
+    pub pid : i32,
+    pub libpath : String,
+    pub libc : MapsEntry,
+    pub dl : DlApi,
+    pub handle : u64
+}
+
+/*
+ * Validated target information shared by every operation
+ */
+struct Target {
+    pid : i32,
+    libpath : String,
+    /* Path of the library as seen by the target */
+    remote_libpath : String,
+    elf_class : u8,
+    libc : MapsEntry
+}
+
+impl Default for Injector {
+    fn default() -> Self {
+        return Injector::new();
     }
 }
 
-fn find_libc(pid : i32) -> Option<MapsEntry> {
-    let mut libc_entry : Option<MapsEntry> = None;
-    let re = Regex::new(r".*/(libc[.\-].*)").ok()?;
-    enum_maps(pid, |entry : MapsEntry| {
-        match re.captures(entry.path.as_str()) {
-            None => return true,
-            Some(_) => {  }
+impl Injector {
+    pub fn new() -> Self {
+        return Injector {
+            pid: None,
+            libpath: None,
+            mode: RTLD_LAZY,
+            all_threads: false,
+            memfd: false,
+            copy: false,
+            entry: None,
+            entry_arg: None,
+            verbose: false
         };
+    }
 
-        libc_entry = Some(entry);
-        return false;
-    });
+    pub fn pid(mut self, pid : i32) -> Self {
+        self.pid = Some(pid);
+        return self;
+    }
 
-    return libc_entry;
-}
+    pub fn library(mut self, libpath : &str) -> Self {
+        self.libpath = Some(String::from(libpath));
+        return self;
+    }
 
-fn find_dlopen(libc_entry : &MapsEntry) -> Result<u64, String> {
-    let libc_file = match File::open(&libc_entry.path) {
-        Ok(f) => f,
-        Err(e) => return Err(format!("Could not open libc file: {}", e))
-    };
-    
-    let mut dlopen_addr : Option<u64> = None;
-    let result = enum_symbols(&libc_file, |ehdr : &ElfW<Elf32_Ehdr, Elf64_Ehdr>, symbol : String, value : u64| -> bool {
-        let mut value = value;
-        if symbol != "__libc_dlopen_mode" {
-            return true;
+    /* Flags passed to dlopen in the target (RTLD_*) */
+    pub fn mode(mut self, mode : i32) -> Self {
+        self.mode = mode;
+        return self;
+    }
+
+    /*
+     * Keep every thread of the target stopped during the operation,
+     * instead of only the one that runs dlopen
+     */
+    pub fn all_threads(mut self, all_threads : bool) -> Self {
+        self.all_threads = all_threads;
+        return self;
+    }
+
+    /*
+     * Copy the library into an anonymous file created in the target
+     * (memfd_create) and load it from there, so it does not need to
+     * be reachable from the target's filesystem. The file descriptor
+     * is left open in the target (close-on-exec)
+     */
+    pub fn memfd(mut self, memfd : bool) -> Self {
+        self.memfd = memfd;
+        return self;
+    }
+
+    /*
+     * Copy the library into the root of the target when it can't
+     * see the file, e.g. when it runs in a container or chroot
+     */
+    pub fn copy_to_root(mut self, copy : bool) -> Self {
+        self.copy = copy;
+        return self;
+    }
+
+    /*
+     * Function exported by the library to call once it is loaded,
+     * with 'arg' as its only argument (or no arguments without it)
+     */
+    pub fn entry(mut self, symbol : &str, arg : Option<&str>) -> Self {
+        self.entry = Some(String::from(symbol));
+        self.entry_arg = arg.map(String::from);
+        return self;
+    }
+
+    /* Print every step of the injection to stdout */
+    pub fn verbose(mut self, verbose : bool) -> Self {
+        self.verbose = verbose;
+        return self;
+    }
+
+    pub fn inject(&self) -> Result<Injection> {
+        let target = self.target()?;
+        let mut remote = self.attach(target.pid)?;
+        let injection = self.load(target, &mut remote)?;
+        remote.detach()?;
+
+        return Ok(injection);
+    }
+
+    /*
+     * Starts 'program' with 'args' and injects the library at the
+     * entry point of the program, after the dynamic loader has run
+     * but before any of its code. The program is killed if the
+     * injection fails, and continues otherwise
+     */
+    pub fn launch(&self, program : &str, args : &[String]) -> Result<Injection> {
+        let mut remote = RemoteProcess::launch(program, args, self.verbose)?;
+        let result = self.run_to_entry(&mut remote)
+            .and_then(|_| self.target_of(remote.pid()))
+            .and_then(|target| self.load(target, &mut remote));
+
+        return match result {
+            Ok(injection) => {
+                remote.detach()?;
+                Ok(injection)
+            },
+            Err(e) => {
+                let _ = remote.kill();
+                Err(e)
+            }
+        };
+    }
+
+    /*
+     * Loads the library through the target's dlopen
+     */
+    fn load(&self, target : Target, remote : &mut RemoteProcess) -> Result<Injection> {
+        let refs = refs_path(target.pid)?;
+        let mut dl = DlApi::resolve(target.pid, &target.libc, self.verbose)?;
+        dl.load_libdl(remote, self.verbose)?;
+        separator!(self.verbose);
+
+        let mut memfd : Option<i32> = None;
+        let dlopen_path = if self.memfd {
+            let data = fs::read(&target.libpath).map_err(|e| Error::io(&target.libpath, e))?;
+            let name = Path::new(&target.libpath).file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+            let fd = remote.memfd(&name, &data)?;
+            memfd = Some(fd);
+            format!("/proc/self/fd/{}", fd)
+        } else {
+            if target.remote_libpath != target.libpath {
+                // Replaced by renaming, as a previous copy may still be mapped in the target
+                let copy = root_path(target.pid, &target.remote_libpath);
+                let tmp = format!("{}.tmp", copy);
+                fs::copy(&target.libpath, &tmp).map_err(|e| Error::io(&tmp, e))?;
+                fs::rename(&tmp, &copy).map_err(|e| Error::io(&copy, e))?;
+                log!(self.verbose, "Copied the library to {}", copy);
+            }
+            target.remote_libpath.clone()
+        };
+
+        // The file that gets mapped, to be recognized in the maps of the target
+        let file_id = match memfd {
+            Some(fd) => file_id(&format!("/proc/{}/fd/{}", target.pid, fd)),
+            None => file_id(&root_path(target.pid, &dlopen_path))
+        };
+        let file_id = match file_id {
+            Ok(id) => id,
+            Err(e) => {
+                if let Some(fd) = memfd {
+                    let _ = remote.close(fd);
+                }
+                return Err(e);
+            }
+        };
+
+        let mode = dl.mode(self.mode) as u32 as u64;
+        let result = remote.call(dl.dlopen, &[RemoteArg::Str(&dlopen_path), RemoteArg::Int(mode)]);
+        if let (Some(fd), Ok(0) | Err(_)) = (memfd, &result) {
+            let _ = remote.close(fd);
+        }
+        // Otherwise the memfd stays open: the loader knows the library by the
+        // path '/proc/self/fd/N', which must not be reused by another injection
+
+        let handle = result?;
+        if handle == 0 {
+            let message = dl.error(remote)?;
+            return Err(Error::DlopenFailed { pid: target.pid, path: target.libpath, message });
         }
 
-        println!("Libc dlopen info: ");
-        println!("\tSymbol: {}", symbol);
-        if ehdr.get_type() != ET_EXEC {
-            println!("\tAddress (rel): {:#x}", value);
-            value += libc_entry.base as u64; // calculate absolute address
+        log!(self.verbose, "Library handle: {:#x}", handle);
+        record_reference(&refs, &dlopen_path)?;
+        let module = find_module(target.pid, file_id, &dlopen_path)?
+            .ok_or_else(|| Error::LibraryNotLoaded { pid: target.pid, path: target.libpath.clone() })?;
+        log!(self.verbose, "Library mappings:");
+        for segment in &module.segments {
+            log!(self.verbose, "\t{:#x}-{:#x} {} {:#x}", segment.base, segment.end, segment.perms(), segment.offset);
         }
-        println!("\tAddress: {:#x}", value);
-        dlopen_addr = Some(value);
-        return false;
-    });
-
-    match result {
-        Err(e) => return Err(format!("Could not enumerate libc symbols: {}", e)),
-        Ok(_) => {  }
+
+        let entry_result = match &self.entry {
+            Some(symbol) => {
+                let addr = dl.symbol(remote, handle, &module, symbol, &target.libpath, self.verbose)?;
+                Some(self.call_entry(remote, symbol, addr)?)
+            },
+            None => None
+        };
+        separator!(self.verbose);
+
+        return Ok(Injection {
+            pid: target.pid,
+            libpath: target.libpath,
+            elf_class: target.elf_class,
+            libc: target.libc,
+            dl,
+            dlopen_path,
+            handle,
+            module,
+            entry_result
+        });
     }
 
-    return match dlopen_addr {
-        Some(addr) => Ok(addr),
-        None => Err(format!("The symbol __libc_dlopen_mode was not found in the target libc"))
-    };
-}
+    fn call_entry(&self, remote : &mut RemoteProcess, symbol : &str, addr : u64) -> Result<u64> {
+        log!(self.verbose, "Calling {} at {:#x}", symbol, addr);
+        let result = match &self.entry_arg {
+            Some(arg) => remote.call(addr, &[RemoteArg::Str(arg)])?,
+            None => remote.call(addr, &[])?
+        };
+        log!(self.verbose, "{} returned: {:#x}", symbol, result);
 
-trait ByteFmt {
-    fn bytearr(&self) -> String;
-    fn bytestr(&self) -> String;
-}
+        return Ok(result);
+    }
 
-impl ByteFmt for Vec<u8> {
-    fn bytearr(&self) -> String {
-        let mut output = String::new();
-        for i in 0..self.len() {
-            if i != 0 {
-                output = format!("{}, {:#x}", output, self[i]);
-            } else {
-                output = format!("{:#x}", self[i]);
+    /*
+     * Runs a process stopped at execve until the entry point of the
+     * program: 'e_entry' plus the load bias, which is the difference
+     * between AT_PHDR in the auxiliary vector and PT_PHDR
+     */
+    fn run_to_entry(&self, remote : &mut RemoteProcess) -> Result<()> {
+        let file = open_exe(remote.pid())?;
+        let ehdr = read_ehdr(&file).map_err(|e| e.in_file(&format!("/proc/{}/exe", remote.pid())))?;
+
+        let auxv = read_auxv(remote.pid(), remote.arch().word_size() as usize)?;
+        let auxv_value = |key : u64| auxv.iter().find(|(k, _)| *k == key).map(|(_, v)| *v);
+        let at_entry = auxv_value(AT_ENTRY)
+            .ok_or_else(|| Error::elf("No AT_ENTRY in the auxiliary vector").in_file(&format!("/proc/{}/auxv", remote.pid())))?;
+
+        let mut phdr_vaddr : Option<u64> = None;
+        ehdr.enum_segments(&file, |segment : &Segment| {
+            if segment.p_type == PT_PHDR {
+                phdr_vaddr = Some(segment.vaddr);
+                return false;
             }
+            return true;
+        });
+
+        // Without PT_PHDR (static executables) the kernel's AT_ENTRY is used as is
+        let load_bias = match (auxv_value(AT_PHDR), phdr_vaddr) {
+            (Some(at_phdr), Some(vaddr)) => at_phdr.wrapping_sub(vaddr),
+            _ => at_entry.wrapping_sub(ehdr.get_entry())
+        };
+        let entry = ehdr.get_entry().wrapping_add(load_bias) & remote.arch().addr_mask();
+
+        log!(self.verbose, "Program entry info:");
+        log!(self.verbose, "	e_entry: {:#x}", ehdr.get_entry());
+        log!(self.verbose, "	Load bias: {:#x}", load_bias);
+        log!(self.verbose, "	Entry point: {:#x}", entry);
+        if entry != at_entry {
+            return Err(Error::EntryMismatch { pid: remote.pid(), entry, at_entry });
         }
-        output = format!("[ {} ]", output);
-        return output;
+
+        remote.run_to(entry)?;
+        separator!(self.verbose);
+        return Ok(());
     }
 
-    fn bytestr(&self) -> String {
-        let mut output = String::new();
-        for i in 0..self.len() {
-            output = format!("{}\\x{:02x}", output, self[i]);
+    /*
+     * Maps the library into the target by hand instead of through
+     * dlopen (see 'mapper::map_library'). The target does not need
+     * a C library, so this also works on static executables
+     */
+    pub fn map(&self) -> Result<Mapping> {
+        let pid = self.target_pid()?;
+        let (libpath, _) = self.check_library(pid)?;
+        let mut remote = self.attach(pid)?;
+        let mut mapping = mapper::map_library(&mut remote, &libpath, self.verbose)?;
+        if let Some(symbol) = &self.entry {
+            let addr = mapper::find_export(&mapping, symbol)?;
+            mapping.entry_result = Some(self.call_entry(&mut remote, symbol, addr)?);
         }
-        output = format!("\"{}\"", output);
-        return output;
+        remote.detach()?;
+        separator!(self.verbose);
+
+        return Ok(mapping);
     }
-}
 
-fn ptwrite(pid : Pid, addr : u64, buf : &mut Vec<u8>) -> Result<(), Errno> {
-    let buflen = buf.len();
-    const datsiz : usize = size_of::<*mut c_void>();
+    /*
+     * Unloads a previously injected library. The handle is retrieved
+     * with 'dlopen(RTLD_NOLOAD)', which takes a reference of its own,
+     * so 'dlclose' is called once for it and once for each reference
+     * recorded by the injections (see 'record_reference'). References
+     * held by the program itself are left alone
+     */
+    pub fn eject(&self) -> Result<Ejection> {
+        let target = self.target()?;
+        let refs = refs_path(target.pid)?;
+        let references = count_references(&refs, &target.remote_libpath)?;
+        log!(self.verbose, "Injected references: {}", references);
+        if references == 0 {
+            return Err(Error::NotInjected { pid: target.pid, path: target.libpath });
+        }
+
+        let dl = DlApi::resolve(target.pid, &target.libc, self.verbose)?;
+        separator!(self.verbose);
 
-    // Add padding for ptrace call (word-aligned)
-    if buflen < datsiz {
-        for i in 0..(datsiz - buflen) {
-            buf.push(0);
+        let file_id = file_id(&root_path(target.pid, &target.remote_libpath))?;
+        let mut remote = self.attach(target.pid)?;
+        let mode = dl.mode(RTLD_NOLOAD | RTLD_LAZY) as u32 as u64;
+        let handle = remote.call(dl.dlopen, &[RemoteArg::Str(&target.remote_libpath), RemoteArg::Int(mode)])?;
+        log!(self.verbose, "Library handle: {:#x}", handle);
+        if handle == 0 {
+            return Err(Error::LibraryNotLoaded { pid: target.pid, path: target.libpath });
         }
-    } else if buflen % datsiz != 0 {
-        for i in 0..datsiz - (buflen % datsiz) {
-            buf.push(0);
+
+        for _ in 0..(references + 1) {
+            let ret = remote.call(dl.dlclose, &[RemoteArg::Ptr(handle)])?;
+            log!(self.verbose, "dlclose returned: {:#x}", ret);
         }
-    }
+        remote.detach()?;
+        forget_references(&refs, &target.remote_libpath)?;
+        separator!(self.verbose);
 
-    for i in 0..(buf.len() / datsiz) {
-        let mut databuf : [u8;datsiz] = [0;datsiz];
-        for j in 0..datsiz {
-            // copy data section into databuf
-            databuf[j] = buf[i * datsiz + j];
+        if find_module(target.pid, file_id, &target.remote_libpath)?.is_some() {
+            return Err(Error::LibraryStillLoaded { pid: target.pid, path: target.libpath });
         }
 
-        let data = unsafe {
-            transmute::<[u8;datsiz], *mut c_void>(databuf)
-        };
-        match unsafe { ptrace::write(pid, (addr + (i * datsiz) as u64) as AddressType, data) } {
-            Err(e) => return Err(e),
-            _ => {  }
+        if target.remote_libpath != target.libpath {
+            let copy = root_path(target.pid, &target.remote_libpath);
+            fs::remove_file(&copy).map_err(|e| Error::io(&copy, e))?;
+            log!(self.verbose, "Removed the copy of the library ({})", copy);
         }
+
+        return Ok(Ejection {
+            pid: target.pid,
+            libpath: target.libpath,
+            libc: target.libc,
+            dl,
+            handle
+        });
     }
 
-    return Ok(());
-}
+    /*
+     * Validates the parameters and gathers what every remote
+     * operation on the target needs
+     */
+    fn target(&self) -> Result<Target> {
+        return self.target_of(self.target_pid()?);
+    }
+
+    fn target_pid(&self) -> Result<i32> {
+        return match self.pid {
+            Some(p) if p > 0 => Ok(p),
+            _ => Err(Error::InvalidArgument(String::from("no valid target PID was specified")))
+        };
+    }
+
+    fn target_of(&self, pid : i32) -> Result<Target> {
+        let (libpath, elf_class) = self.check_library(pid)?;
 
-fn ptread(pid : Pid, addr : u64, size : usize) -> Result<Vec<u8>, Errno> {
-    let mut buf : Vec<u8> = vec![];
-    let mut size = size;
-    const datsiz : usize = size_of::<c_long>();
+        // Symbols are read from the libc mapped in the target, which is the
+        // 32-bit one (e.g. /lib32 or /usr/lib/i386-linux-gnu) for i386 processes
+        let libc = find_libc(pid)?;
+        let libc_class = self.elf_class_of(&root_path(pid, &libc.path), "Libc")?;
+        if libc_class != elf_class {
+            return Err(Error::ClassMismatch { library: libc_class, process: elf_class });
+        }
+        log!(self.verbose, "Target libc info:");
+        log!(self.verbose, "\tBase Address: {:#x}", libc.base);
+        log!(self.verbose, "\tPath: {}", libc.path);
+        separator!(self.verbose);
 
-    if size < datsiz {
-        size = datsiz;
-    } else if size % datsiz != 0 {
-        let diff = datsiz - (size % datsiz);
-        size += diff;
+        let remote_libpath = self.remote_libpath(pid, &libpath)?;
+        return Ok(Target { pid, libpath, remote_libpath, elf_class, libc });
     }
 
-    for i in 0..(size / datsiz) {
-        let data = match ptrace::read(pid, (addr + (i * datsiz) as u64) as AddressType) {
-            Ok(dat) => dat,
-            Err(e) => return Err(e),
+    /*
+     * Checks that the library fits the process 'pid', and returns
+     * its canonical path and the ELF class of both
+     */
+    fn check_library(&self, pid : i32) -> Result<(String, u8)> {
+        let libpath = match &self.libpath {
+            Some(path) => canonicalize(path).map_err(|e| Error::io(path, e))?.to_string_lossy().into_owned(),
+            None => return Err(Error::InvalidArgument(String::from("no library path was specified")))
         };
 
-        let databuf : [u8;datsiz] = unsafe {
-            transmute::<c_long, [u8;datsiz]>(data)
-        };
-        buf.extend(databuf);
+        let lib_class = self.elf_class_of(&libpath, "Library")?;
+        let exe = open_exe(pid)?;
+        let elf_class = self.elf_class(&exe, &format!("/proc/{}/exe", pid), "Process")?;
+        if lib_class != elf_class {
+            return Err(Error::ClassMismatch { library: lib_class, process: elf_class });
+        }
+
+        return Ok((libpath, elf_class));
     }
 
-    return Ok(buf);
-}
+    /*
+     * Finds the path under which the target can open the library. A
+     * target with another root (mount namespace or chroot) may see
+     * the same file under the same path (e.g. a bind mount), or else
+     * gets a copy in its root if 'copy_to_root' is set
+     */
+    fn remote_libpath(&self, pid : i32, libpath : &str) -> Result<String> {
+        if self.memfd || shares_root(pid)? {
+            return Ok(String::from(libpath));
+        }
+        log!(self.verbose, "The process {} has its own root (mount namespace or chroot)", pid);
 
-fn call_dlopen(pid : i32, elf_class : u8, dlopen_addr : u64, libpath : &String, mode : i32) -> Result<u64, String> {
-    let libpath = format!("{}\x00", libpath); // ensure null terminator
-    let pid = Pid::from_raw(pid);
-    println!("Attaching to process...");
-    match ptrace::attach(pid) {
-        Err(e) => return Err(format!("Could not attach to process {}: {}", pid, e)),
-        _ => {  }
-    }
-    wait::wait();
-    println!("Attached to process {}", pid);
-
-    let old_regs = match ptrace::getregs(pid) {
-        Ok(r) => r,
-        Err(e) => {
-            ptrace::detach(pid, None);
-            return Err(format!("Could not get registers from process: {}", e));
+        let local = file_id(libpath)?;
+        if let Ok(remote) = file_id(&root_path(pid, libpath)) {
+            if remote == local {
+                log!(self.verbose, "The library is visible to the process at the same path");
+                return Ok(String::from(libpath));
+            }
         }
-    };
-    println!("Registers: {:x?}", old_regs);
-    let mut regs = old_regs;
 
-    let mut stackbuf : Vec<u8> = vec![];
-    /*
-    let modebuf = unsafe {
-        transmute::<i32, [u8;size_of::<i32>()]>(mode)
-    };
-    stackbuf.extend(modebuf);
-    */
-    let pathbuf = libpath.as_bytes();
-    stackbuf.extend(pathbuf);
-    println!("Stack Buffer (size: {}): {}", stackbuf.len(), stackbuf.bytestr());
-
-    let mut payload : Vec<u8> = vec![];
-    if elf_class == ELFCLASS32 {
-        /*
-         * Payload
-         * push ecx
-         * push ebx
-         * call eax
-         * int3
-         */
-        payload.extend([0x51, 0x53, 0xff, 0xd0, 0xcc]);
-    } else if elf_class == ELFCLASS64 {
-        /*
-         * Payload
-         * call rax
-         * int3
-         */
-        payload.extend([0xff, 0xd0, 0xcc]);
-    } else {
-        ptrace::detach(pid, None);
-        return Err(format!("Invalid process ELF class"));
+        if !self.copy {
+            return Err(Error::InvalidArgument(format!(
+                "the library is not visible from the root of process {} (copy it there or use memfd mode)", pid
+            )));
+        }
+
+        let filename = Path::new(libpath).file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+        return Ok(format!("{}/rust-injector-{}", COPY_DIR, filename));
     }
 
-    println!("Payload Buffer (size: {}): {}", payload.len(), payload.bytestr());
+    fn attach(&self, pid : i32) -> Result<RemoteProcess> {
+        let mut remote = RemoteProcess::attach(pid, self.verbose)?;
+        if self.in_loader_startup(&remote)? {
+            log!(self.verbose, "The process is still being set up by the dynamic loader");
+            self.run_to_entry(&mut remote)?;
+        }
 
-    regs.rsp -= stackbuf.len() as u64;
-    regs.rsp &= 0xfffffffffffffff0; // align stack
-    match ptwrite(pid, regs.rsp, &mut stackbuf) {
-        Err(e) => {
-            ptrace::detach(pid, None);
-            return Err(format!("Unable to write stack buffer into target process: {}", e));
+        if self.all_threads {
+            let count = remote.stop_threads()?;
+            log!(self.verbose, "Stopped {} other thread(s)", count);
         }
 
-        _ => {  }
-    }
-    println!("Stack buffer written into target process");
-
-    if elf_class == ELFCLASS32 {
-        regs.rax = dlopen_addr;
-        regs.rbx = regs.rsp;
-        regs.rcx = mode as u64;
-    } else {
-        regs.rax = dlopen_addr;
-        regs.rdi = regs.rsp; // arg0
-        regs.rsi = mode as u64; // arg1
+        return Ok(remote);
     }
 
-    let mut old_code = match ptread(pid, regs.rip, payload.len()) {
-        Ok(buf) => buf,
-        Err(e) => {
-            ptrace::detach(pid, None);
-            return Err(format!("Unable to read code buffer from target process: {}", e));
+    /*
+     * Whether the process was caught while the dynamic loader sets it
+     * up (as with '--wait'): the thread is in the loader, found through
+     * AT_BASE, and libc is not relocated yet, so none of its functions
+     * can be called before the entry point of the program
+     */
+    fn in_loader_startup(&self, remote : &RemoteProcess) -> Result<bool> {
+        let pid = remote.pid();
+        let auxv = read_auxv(pid, remote.arch().word_size() as usize)?;
+        let at_base = match auxv.iter().find(|(k, _)| *k == AT_BASE) {
+            Some((_, base)) if *base != 0 => *base,
+            _ => return Ok(false) // no interpreter
+        };
+
+        let ip = remote.arch().ip(&remote.getregs()?);
+        let in_loader = enum_modules(pid)?.iter()
+            .filter(|m| m.base() == at_base)
+            .any(|m| m.segments.iter().any(|s| s.base <= ip && ip < s.end));
+        if !in_loader {
+            return Ok(false);
         }
-    };
-    println!("Code buffer read from target process");
-    println!("Code: {}", old_code.bytestr());
 
-    match ptwrite(pid, regs.rip, &mut payload) {
-        Err(e) => {
-            ptrace::detach(pid, None);
-            return Err(format!("Unable to write payload buffer into target process: {}", e));
+        // The musl loader is the libc itself
+        let libc = find_libc(pid)?;
+        if LibcFlavour::of(&libc) == LibcFlavour::Musl {
+            return Ok(false);
         }
 
-        _ => {  }
+        return Ok(!libc_relocated(remote, &libc)?);
     }
-    println!("Payload injected into target process");
 
-    match ptrace::setregs(pid, regs) {
-        Err(e) => {
-            ptwrite(pid, regs.rip, &mut old_code);
-            ptrace::detach(pid, None);
-            return Err(format!("Unable to set registers on target process: {}", e));
-        }
+    fn elf_class_of(&self, path : &str, kind : &str) -> Result<u8> {
+        let file = File::open(path).map_err(|e| Error::io(path, e))?;
+        return self.elf_class(&file, path, kind);
+    }
+
+    fn elf_class(&self, file : &File, path : &str, kind : &str) -> Result<u8> {
+        let ehdr = read_ehdr(file).map_err(|e| e.in_file(path))?;
+
+        log!(self.verbose, "{} ELF info: ", kind);
+        log!(self.verbose, "\tClass: {}", ehdr.get_class());
+        log!(self.verbose, "\tMagic: {}", String::from_utf8_lossy(ehdr.get_magic().as_slice()));
+        log!(self.verbose, "\tHeader: {:?}", ehdr);
+        separator!(self.verbose);
 
-        _ => {  }
+        return Ok(ehdr.get_class());
     }
-    println!("Set registers on target process");
-
-    match ptrace::cont(pid, None) {
-        Err(e) => {
-            ptwrite(pid, regs.rip, &mut old_code);
-            ptrace::setregs(pid, old_regs);
-            ptrace::detach(pid, None);
-            return Err(format!("Unable to continue target process: {}", e));
+}
+
+/*
+ * Device and inode of a file
+ */
+fn file_id(path : &str) -> Result<(u64, u64)> {
+    let metadata = fs::metadata(path).map_err(|e| Error::io(path, e))?;
+    return Ok((metadata.dev(), metadata.ino()));
+}
+
+/*
+ * Finds the mappings of the file identified by 'file_id' (device
+ * and inode, from 'stat') in the process 'pid'. The device numbers
+ * of the maps may differ from the ones of 'stat' (btrfs subvolumes,
+ * overlayfs), so a module with the same inode but another device is
+ * checked through '/proc/<pid>/map_files', or by its 'path' in the
+ * target if that is not readable
+ */
+fn find_module(pid : i32, file_id : (u64, u64), path : &str) -> Result<Option<Module>> {
+    let (dev, inode) = file_id;
+    for module in enum_modules(pid)?.into_iter().filter(|m| m.inode == inode) {
+        if module.dev == dev {
+            return Ok(Some(module));
         }
 
-        _ => {  }
+        let matches = match module_file_id(pid, &module) {
+            Ok(id) => id == file_id,
+            Err(_) => module.path == path
+        };
+        if matches {
+            return Ok(Some(module));
+        }
     }
-    println!("Continued target process");
-    println!("Waiting for target process to stop...");
-    wait::waitpid(pid, Some(wait::WaitPidFlag::WSTOPPED));
-    println!("Target process stopped");
-
-    let handle : Result<u64, String> = match ptrace::getregs(pid) {
-        Ok(r) => {
-            println!("Post injection registers: {:x?}", r);
-            Ok(r.rax)
-        },
-        Err(e) => Err(format!("Unable to read registers from target process: {}", e))
-    };
 
-    ptwrite(pid, old_regs.rip, &mut old_code);
-    ptrace::setregs(pid, old_regs);
-    ptrace::detach(pid, None);
+    return Ok(None);
+}
 
-    return handle;
+/*
+ * File recording the dlopen references taken by injections into the
+ * process 'pid', one dlopen path per line. It is named after the PID
+ * and the start time of the process, so a reused PID starts afresh
+ */
+fn refs_path(pid : i32) -> Result<String> {
+    let dir = std::env::temp_dir().join(REFS_DIR);
+    return Ok(format!("{}/{}-{}", dir.to_string_lossy(), pid, start_time(pid)?));
 }
 
-pub fn inject(pid : i32, elf_class : u8, libpath : &String) -> Result<(), String> {
-    let libc_entry = match find_libc(pid) {
-        Some(entry) => entry,
-        None => return Err(format!("Could not find libc in target process"))
-    };
-    println!("Target libc info:");
-    println!("\tBase Address: {:#x}", libc_entry.base);
-    println!("\tPath: {}", libc_entry.path);
-    separator!();
-    let dlopen_addr = match find_dlopen(&libc_entry) {
-        Ok(addr) => addr,
-        Err(e) => return Err(format!("Could not find dlopen in libc: {}", e))
-    };
-    separator!();
+/*
+ * Records a reference taken by a successful dlopen of 'path'
+ */
+fn record_reference(refs : &str, path : &str) -> Result<()> {
+    if let Some(dir) = Path::new(refs).parent() {
+        fs::create_dir_all(dir).map_err(|e| Error::io(&dir.to_string_lossy(), e))?;
+    }
 
-    let libc_file = match File::open(libc_entry.path) {
-        Ok(file) => file,
-        Err(e) => return Err(format!("Could not open libc file"))
-    };
+    let mut file = OpenOptions::new().create(true).append(true).open(refs).map_err(|e| Error::io(refs, e))?;
+    return file.write_all(format!("{}\n", path).as_bytes()).map_err(|e| Error::io(refs, e));
+}
 
-    let handle = match call_dlopen(pid, elf_class, dlopen_addr, libpath, RTLD_LAZY) {
-        Ok(h) => h,
-        Err(e) => return Err(format!("Could not run dlopen on target process: {}", e))
+/*
+ * Lists the references recorded in 'refs' (none if it doesn't exist)
+ */
+fn read_references(refs : &str) -> Result<Vec<String>> {
+    return match fs::read_to_string(refs) {
+        Ok(content) => Ok(content.lines().map(String::from).collect()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(vec![]),
+        Err(e) => Err(Error::io(refs, e))
     };
+}
 
-    println!("Library handle: {:#x}", handle);
-    separator!();
+fn count_references(refs : &str, path : &str) -> Result<usize> {
+    return Ok(read_references(refs)?.iter().filter(|r| *r == path).count());
+}
 
-    return Ok(());
-}
\ No newline at end of file
+/*
+ * Drops the references to 'path' from 'refs', once they were released
+ */
+fn forget_references(refs : &str, path : &str) -> Result<()> {
+    let remaining : Vec<String> = read_references(refs)?.into_iter().filter(|r| r != path).collect();
+    if remaining.is_empty() {
+        return fs::remove_file(refs).map_err(|e| Error::io(refs, e));
+    }
+
+    let content : String = remaining.iter().map(|r| format!("{}\n", r)).collect();
+    return fs::write(refs, content).map_err(|e| Error::io(refs, e));
+}
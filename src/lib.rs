@@ -1,12 +1,38 @@
-extern crate proc_macro;
-use proc_macro::TokenStream;
-
-#[proc_macro]
-pub fn elfw(code : TokenStream) -> TokenStream {
-    return format!("
-        match self {{
-            ElfW::Elf32(e) => {0},
-            ElfW::Elf64(e) => {0}
-        }}
-    ", code).parse().unwrap();
+/*
+ * Rust Injector - Shared library injector for Linux
+ * The 'Injector' builder is the main entry point; the
+ * 'proc' and 'elf' modules are exposed for tooling that
+ * needs to inspect targets on its own.
+ */
+
+macro_rules! log {
+    ($verbose:expr, $($arg:tt)*) => {
+        if $verbose {
+            println!($($arg)*);
+        }
+    }
+}
+
+macro_rules! separator {
+    ($verbose:expr) => {
+        log!($verbose, "====================");
+    }
 }
+
+pub mod error;
+pub mod proc;
+pub mod elf;
+pub mod mem;
+pub mod arch;
+pub mod remote;
+pub mod dlfcn;
+pub mod mapper;
+pub mod injector;
+
+pub use injector::{Injector, Injection, Ejection};
+pub use remote::{RemoteProcess, RemoteArg};
+pub use mem::{RemoteMemory, MemoryBackend};
+pub use arch::{Arch, Syscall};
+pub use dlfcn::{DlApi, DlopenSource, LibcFlavour};
+pub use mapper::Mapping;
+pub use error::{Error, Phase, Result};
@@ -1,12 +1,14 @@
 use std::env;
 use std::path::Path;
-use std::fs::File;
+use std::process;
+use std::time::{Duration, Instant};
 
-mod proc;
-mod elf;
-mod injector;
-use elf::elfdefs::*;
-use nix::unistd::geteuid;
+use rust_injector::{proc, dlfcn, elf};
+use rust_injector::{Injector, Injection, Error, Result};
+use rust_injector::elf::elfdefs::{ElfW_Ehdr, ELFCLASS32, ELFCLASS64};
+use nix::unistd::{geteuid, Pid};
+use regex::Regex;
+use nix::sys::wait::{waitpid, WaitStatus};
 
 macro_rules! separator {
     () => {
@@ -14,118 +16,411 @@ macro_rules! separator {
     }
 }
 
+/*
+ * What is done to the target(s)
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Operation {
+    Inject,
+    Eject,
+    ManualMap
+}
+
+impl Operation {
+    fn done(&self) -> &'static str {
+        return match self {
+            Operation::Inject => "Injected",
+            Operation::Eject => "Ejected",
+            Operation::ManualMap => "Mapped"
+        };
+    }
+
+    /* What the address returned by 'run' is */
+    fn result(&self) -> &'static str {
+        return match self {
+            Operation::ManualMap => "base",
+            _ => "handle"
+        };
+    }
+}
+
 fn help() {
-    println!("usage: ./rust-injector [-n NAME][-f FILENAME][-p PID] SHARED_LIB");
+    println!("usage: ./rust-injector [-v][--eject|--manual-map][--all-threads][--all][--memfd][--copy][--entry SYMBOL [--arg STRING]][--wait [--timeout SECS]][-n NAME][-f FILENAME][-p PID] SHARED_LIB");
+    println!("       ./rust-injector [-v][--memfd][--entry SYMBOL [--arg STRING]] --launch SHARED_LIB -- PROGRAM [ARGS...]");
+    println!("       ./rust-injector modules [-n NAME][-f FILENAME][-p PID] [REGEX]");
+}
+
+/*
+ * Prints the error and terminates with the exit
+ * code that corresponds to its kind
+ */
+fn fail(e : Error) -> ! {
+    eprintln!("Error: {}", e);
+    process::exit(e.exit_code());
+}
+
+/*
+ * Prints what is known about the target process. Fails if
+ * it is gone already
+ */
+fn print_target(libpath : &str, pid : i32) -> Result<()> {
+    /* The 'filename' entered by the user does not need to be a full path,
+     * therefore it will be always retrieved (to have the full path)
+     */
+    let fname = proc::exepath_from_pid(pid)?;
+    let name = proc::name_from_pid(pid).unwrap_or_default();
+
+    println!("General info: ");
+    println!("\tLibrary path: {}", libpath);
+    println!("\tProcess ID: {}", pid);
+    println!("\tProcess Name: {}", name);
+    println!("\tProcess Filename: {}", fname);
+    separator!();
+    return Ok(());
+}
+
+/*
+ * Prints where the library was found mapped after loading it
+ */
+fn print_module(injection : &Injection) {
+    println!("Load base: {:#x}", injection.module.base());
+    println!("Segments: ");
+    for segment in &injection.module.segments {
+        println!("\t{:#x}-{:#x} {} (offset {:#x})", segment.base, segment.end, segment.perms(), segment.offset);
+    }
+}
+
+/*
+ * Prints the return value of the entry function, if it was called.
+ * Its type is unknown, so the low 32 bits are also shown as an 'int'
+ */
+fn print_entry(result : Option<u64>) {
+    if let Some(value) = result {
+        println!("Entry returned: {:#x} (int: {})", value, value as u32 as i32);
+    }
+}
+
+/*
+ * Runs the operation and prints the details, returning the
+ * library handle (or the base address of a manual mapping)
+ */
+fn run(injector : &Injector, op : Operation) -> Result<u64> {
+    if op == Operation::Eject {
+        let ejection = injector.eject()?;
+        println!("Libc: {} ({})", ejection.dl.flavour, ejection.libc.path);
+        println!("Loader: {} (dlclose at {:#x})", ejection.dl.source, ejection.dl.dlclose);
+        println!("Library handle: {:#x}", ejection.handle);
+        return Ok(ejection.handle);
+    }
+
+    if op == Operation::ManualMap {
+        let mapping = injector.map()?;
+        println!("Base address: {:#x}", mapping.base);
+        println!("Size: {:#x}", mapping.size);
+        println!("Relocations: {} ({} imported symbols)", mapping.relocations, mapping.imports);
+        println!("Initializers run: {}", mapping.initializers);
+        print_entry(mapping.entry_result);
+        return Ok(mapping.base);
+    }
+
+    let injection = injector.inject()?;
+    println!("Libc: {} ({})", injection.dl.flavour, injection.libc.path);
+    println!("Loader: {} at {:#x}", injection.dl.source, injection.dl.dlopen);
+    if injection.dlopen_path != injection.libpath {
+        println!("Loaded from: {}", injection.dlopen_path);
+    }
+    println!("Library handle: {:#x}", injection.handle);
+    print_module(&injection);
+    print_entry(injection.entry_result);
+    return Ok(injection.handle);
+}
+
+/*
+ * Runs the operation on every process of 'pids', continuing past
+ * failures, and prints a summary. Exits with the exit code of the
+ * first failure, if any. 'wait' holds the deadline of '--wait'
+ */
+fn run_all(injector : &Injector, libpath : &str, pids : &[i32], op : Operation, wait : Option<Option<Instant>>) -> ! {
+    let mut results : Vec<(i32, String, Result<u64>)> = vec![];
+    for pid in pids {
+        let pid = *pid;
+        let name = proc::name_from_pid(pid).unwrap_or_default();
+        let result = print_target(libpath, pid).and_then(|_| match wait {
+            Some(deadline) => dlfcn::wait_libc(pid, deadline).map(|_| ()),
+            None => Ok(())
+        }).and_then(|_| run(&injector.clone().pid(pid), op));
+
+        separator!();
+        match &result {
+            Ok(_) => println!("{} successfully!", op.done()),
+            Err(e) => eprintln!("Error: {}", e)
+        }
+        separator!();
+        results.push((pid, name, result));
+    }
+
+    println!("Summary: ");
+    let mut exit_code = 0;
+    for (pid, name, result) in &results {
+        match result {
+            Ok(addr) => println!("\t{} ({}): OK ({} {:#x})", pid, name, op.result(), addr),
+            Err(e) => {
+                println!("\t{} ({}): FAILED ({})", pid, name, e);
+                if exit_code == 0 {
+                    exit_code = e.exit_code();
+                }
+            }
+        }
+    }
+    let succeeded = results.iter().filter(|(_, _, result)| result.is_ok()).count();
+    println!("{}/{} processes succeeded", succeeded, results.len());
+    process::exit(exit_code);
+}
+
+/*
+ * Starts the program with the library injected at its entry point,
+ * then waits for it and exits with its exit code
+ */
+fn launch_and_inject(injector : &Injector, libpath : &str, program : &str, args : &[String]) {
+    println!("General info: ");
+    println!("\tLibrary path: {}", libpath);
+    println!("\tProgram: {}", program);
+    println!("\tArguments: {:?}", args);
+    separator!();
+
+    let injection = injector.launch(program, args).unwrap_or_else(|e| fail(e));
+    println!("Process ID: {}", injection.pid);
+    println!("Libc: {} ({})", injection.dl.flavour, injection.libc.path);
+    println!("Loader: {} at {:#x}", injection.dl.source, injection.dl.dlopen);
+    println!("Library handle: {:#x}", injection.handle);
+    print_module(&injection);
+    print_entry(injection.entry_result);
+    separator!();
+    println!("Injected successfully!");
+
+    let pid = Pid::from_raw(injection.pid);
+    loop {
+        match waitpid(pid, None) {
+            Ok(WaitStatus::Exited(_, code)) => process::exit(code),
+            Ok(WaitStatus::Signaled(_, signal, _)) => process::exit(128 + signal as i32),
+            Ok(_) => continue,
+            Err(_) => process::exit(0)
+        }
+    }
+}
+
+/*
+ * 'modules' subcommand: lists the files mapped in the target,
+ * optionally only those whose path matches a regex
+ */
+fn list_modules(args : &[String]) -> ! {
+    let mut prev = String::new();
+    let mut pid : i32 = 0;
+    let mut name = String::new();
+    let mut fname = String::new();
+    let mut pattern : Option<String> = None;
+    for arg in args {
+        match (prev.as_str(), arg.as_str()) {
+            (_, "-n" | "-p" | "-f") if prev.is_empty() => { prev = arg.clone(); continue; },
+            ("-n", _) => name = arg.clone(),
+            ("-p", _) => pid = arg.parse().unwrap_or(0),
+            ("-f", _) => fname = arg.clone(),
+            _ => pattern = Some(arg.clone())
+        }
+        prev.clear();
+    }
+
+    if pid <= 0 {
+        pid = match (name.is_empty(), fname.is_empty()) {
+            (false, _) => proc::pid_from_name(&name),
+            (true, false) => proc::pid_from_fname(&fname),
+            (true, true) => {
+                help();
+                process::exit(Error::InvalidArgument(String::new()).exit_code());
+            }
+        }.unwrap_or_else(|e| fail(e));
+    }
+
+    let re = pattern.map(|p| Regex::new(&p).map_err(Error::from).unwrap_or_else(|e| fail(e)));
+    let modules = proc::enum_modules(pid).unwrap_or_else(|e| fail(e));
+
+    println!("Modules of process {} ({}): ", pid, proc::name_from_pid(pid).unwrap_or_default());
+    let mut count = 0;
+    for module in modules.iter().filter(|m| re.as_ref().is_none_or(|re| re.is_match(&m.path))) {
+        let file = proc::open_module(pid, module).ok();
+        let class = match file.as_ref().and_then(|f| elf::read_ehdr(f).ok()).map(|ehdr| ehdr.get_class()) {
+            Some(ELFCLASS32) => "ELF32",
+            Some(ELFCLASS64) => "ELF64",
+            _ => "-"
+        };
+        let build_id = match file.as_ref().and_then(elf::read_build_id) {
+            Some(id) => id.iter().map(|b| format!("{:02x}", b)).collect(),
+            None => String::from("-")
+        };
+
+        println!("{}{}", module.path, if module.segments[0].deleted { " (deleted)" } else { "" });
+        println!("\tBase: {:#x}", module.base());
+        println!("\tEnd: {:#x}", module.end());
+        println!("\tSize: {:#x}", module.size());
+        println!("\tClass: {}", class);
+        println!("\tBuild ID: {}", build_id);
+        println!("\tSegments: ");
+        for segment in &module.segments {
+            println!("\t\t{:#x}-{:#x} {} (offset {:#x})", segment.base, segment.end, segment.perms(), segment.offset);
+        }
+        count += 1;
+    }
+    separator!();
+    println!("{} module(s)", count);
+    process::exit(0);
 }
 
 fn main() {
     println!("[ Rust Injector ] by rdbo");
     separator!();
 
-    assert!(geteuid().is_root(), "Please run as root");
+    if !geteuid().is_root() {
+        eprintln!("Please run as root");
+        process::exit(1);
+    }
 
-    let mut args : Vec<String> = env::args().collect();
-    args = args[1..].to_vec(); // remove first argument (program)
-    if args.len() != 3 {
-        help();
-        return;
+    let mut args : Vec<String> = env::args().skip(1).collect(); // remove first argument (program)
+    if args.first().map(String::as_str) == Some("modules") {
+        list_modules(&args[1..]);
     }
 
+    // Everything after '--' is the command line of the program to launch
+    let mut command : Vec<String> = match args.iter().position(|arg| arg == "--") {
+        Some(i) => args.split_off(i).into_iter().skip(1).collect(),
+        None => vec![]
+    };
+
     let mut prev = String::new();
     let mut libpath = String::new();
     let mut pid : i32 = 0;
     let mut name = String::new();
     let mut fname = String::new();
+    let mut verbose = false;
+    let mut op = Operation::Inject;
+    let mut all_threads = false;
+    let mut launch = false;
+    let mut wait = false;
+    let mut all = false;
+    let mut memfd = false;
+    let mut copy = false;
+    let mut entry : Option<String> = None;
+    let mut entry_arg : Option<String> = None;
+    let mut timeout : Option<Duration> = None;
 
     for arg in args {
         match arg.as_str() {
-            "-n" | "-p" | "-f" => { prev = arg; continue; },
+            "-n" | "-p" | "-f" | "--timeout" | "--entry" | "--arg" => { prev = arg; continue; },
+            "-v" => { verbose = true; continue; },
+            "--eject" => { op = Operation::Eject; continue; },
+            "--manual-map" => { op = Operation::ManualMap; continue; },
+            "--all-threads" => { all_threads = true; continue; },
+            "--launch" => { launch = true; continue; },
+            "--wait" => { wait = true; continue; },
+            "--all" => { all = true; continue; },
+            "--memfd" => { memfd = true; continue; },
+            "--copy" => { copy = true; continue; },
             _ => {
                 match prev.as_str() {
                     "-n" => { name = arg; prev.clear() },
                     "-p" => { pid = arg.parse().unwrap_or(0); prev.clear() },
                     "-f" => { fname = arg; prev.clear() },
+                    "--entry" => { entry = Some(arg); prev.clear() },
+                    "--arg" => { entry_arg = Some(arg); prev.clear() },
+                    "--timeout" => {
+                        match arg.parse::<f64>().ok().and_then(|secs| Duration::try_from_secs_f64(secs).ok()) {
+                            Some(t) => timeout = Some(t),
+                            None => fail(Error::InvalidArgument(format!("invalid timeout \"{}\"", arg)))
+                        }
+                        prev.clear()
+                    },
                     _ => libpath = arg
                 }
             }
         }
     }
 
-    if libpath == "" || (pid <= 0 && name == "" && fname == "") {
+    let no_target = if launch {
+        command.is_empty()
+    } else if all {
+        name.is_empty() && fname.is_empty()
+    } else {
+        pid <= 0 && name.is_empty() && fname.is_empty()
+    };
+    if libpath.is_empty() || no_target {
         help();
-        return;
+        process::exit(Error::InvalidArgument(String::new()).exit_code());
     }
 
-    let lib_exists = Path::new(&libpath).is_file();
-    assert!(lib_exists, "The library \"{}\" does not exist", libpath);
+    if !Path::new(&libpath).is_file() {
+        fail(Error::InvalidArgument(format!("the library \"{}\" does not exist", libpath)));
+    }
 
-    if pid <= 0 {
-        if name != "" {
-            pid = match proc::pid_from_name(&name) {
-                Some(p) => p,
-                _ => panic!("Unable to get PID from name: {}", name)
-            }
-        } else {
-            pid = match proc::pid_from_fname(&fname) {
-                Some(p) => p,
-                _ => panic!("Unable to get PID from filename: {}", fname)
-            }
-        }
+    if entry_arg.is_some() && entry.is_none() {
+        fail(Error::InvalidArgument(String::from("--arg requires --entry")));
     }
-    
-    if name == "" {
-        if let Some(option) = proc::name_from_pid(pid) {
-            name = option;
-        }
+
+    let mut injector = Injector::new()
+        .library(&libpath)
+        .all_threads(all_threads)
+        .memfd(memfd)
+        .copy_to_root(copy)
+        .verbose(verbose);
+    if let Some(symbol) = &entry {
+        injector = injector.entry(symbol, entry_arg.as_deref());
     }
-    
-    /* The 'filename' entered by the user does not need to be a full path,
-     * therefore it will be always retrieved (to have the full path)
-     */
-    if let Some(option) = proc::exepath_from_pid(pid) {
-        fname = option;
-    } else {
-        panic!("Unable to retrieve process executable path!");
+
+    if launch {
+        if op != Operation::Inject {
+            fail(Error::InvalidArgument(String::from("--launch only supports injecting with dlopen")));
+        }
+        let program = command.remove(0);
+        launch_and_inject(&injector, &libpath, &program, &command);
+        return;
     }
 
-    println!("General info: ");
-    println!("\tLibrary path: {}", libpath);
-    println!("\tProcess ID: {}", pid);
-    println!("\tProcess Name: {}", name);
-    println!("\tProcess Filename: {}", fname);
-    separator!();
+    let deadline = timeout.map(|t| Instant::now() + t);
+    if pid <= 0 || all {
+        if wait {
+            println!("Waiting for the target process...");
+        }
 
-    let libfile = File::open(&libpath).expect("Unable to open library file");
-    let lib_ehdr = match elf::read_ehdr(&libfile) {
-        Ok(ehdr) => ehdr,
-        Err(e) => panic!("Unable to read library ELF file: {}", e)
-    };
+        let result = match (wait, name.is_empty()) {
+            (false, false) => proc::pid_from_name(&name),
+            (false, true) => proc::pid_from_fname(&fname),
+            (true, false) => proc::wait_pid_from_name(&name, deadline),
+            (true, true) => proc::wait_pid_from_fname(&fname, deadline)
+        };
 
-    println!("Library ELF info: ");
-    println!("\tClass: {}", lib_ehdr.get_class());
-    println!("\tMagic: {}", String::from_utf8_lossy(lib_ehdr.get_magic().as_slice()));
-    println!("\tHeader: {:?}", lib_ehdr);
-    separator!();
+        pid = result.unwrap_or_else(|e| fail(e));
+    }
 
-    let exefile = File::open(fname).expect("Unable to open process executable file");
-    let exe_ehdr = match elf::read_ehdr(&exefile) {
-        Ok(ehdr) => ehdr,
-        Err(e) => panic!("Unable to read process ELF file: {}", e)
-    };
+    if all {
+        let result = if !name.is_empty() {
+            proc::pids_from_name(&name)
+        } else {
+            proc::pids_from_fname(&fname)
+        };
 
-    println!("Process ELF info: ");
-    println!("\tClass: {}", exe_ehdr.get_class());
-    println!("\tMagic: {}", String::from_utf8_lossy(exe_ehdr.get_magic().as_slice()));
-    println!("\tHeader: {:?}", exe_ehdr);
-    separator!();
+        let pids = result.unwrap_or_else(|e| fail(e));
+        run_all(&injector, &libpath, &pids, op, wait.then_some(deadline));
+    }
 
-    assert!(
-        lib_ehdr.get_class() == exe_ehdr.get_class(),
-        "The ELF classes from the library and the process don't match. Make sure they are the same architecture!"
-    );
+    // A process that was just started may not have loaded libc yet
+    if wait {
+        dlfcn::wait_libc(pid, deadline).unwrap_or_else(|e| fail(e));
+    }
 
-    match injector::inject(pid, exe_ehdr.get_class(), &libpath) {
-        Ok(_) => println!("Injected successfully!"),
-        Err(e) => panic!("Unable to inject: {}", e)
+    print_target(&libpath, pid).unwrap_or_else(|e| fail(e));
+    match run(&injector.pid(pid), op) {
+        Ok(_) => {
+            separator!();
+            println!("{} successfully!", op.done());
+        },
+        Err(e) => fail(e)
     }
 }
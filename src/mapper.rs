@@ -0,0 +1,390 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::os::unix::fs::FileExt;
+use nix::sys::mman::ProtFlags;
+
+use crate::proc::{enum_maps, root_path, MapsEntry};
+use crate::elf::{read_ehdr, enum_dynamic, enum_relocations, enum_symbols_in, Relocation, Segment, Symbol, SymbolTable};
+use crate::elf::elfdefs::*;
+use crate::error::{Error, Result};
+use crate::remote::{RemoteProcess, RemoteArg, PAGE_SIZE, align_up};
+
+/*
+ * Result of a successful manual mapping
+ */
+#[derive(Debug)]
+pub struct Mapping {
+    pub pid : i32,
+    pub libpath : String,
+    /* Load bias of the library (address of virtual address 0) */
+    pub base : u64,
+    /* Size of the mapping, which starts at the first PT_LOAD page */
+    pub size : u64,
+    pub relocations : usize,
+    /* Symbols resolved in the modules of the target */
+    pub imports : usize,
+    /* DT_INIT and DT_INIT_ARRAY functions that were run */
+    pub initializers : usize,
+    /* Return value of the entry function, if one was called */
+    pub entry_result : Option<u64>
+}
+
+/*
+ * Address a symbol resolves to. GNU indirect functions resolve
+ * to whatever their resolver returns when called in the target
+ */
+#[derive(Debug, Clone, Copy)]
+enum Resolved {
+    Addr(u64),
+    Ifunc(u64)
+}
+
+/*
+ * Word of the image whose value is only known once the
+ * image runs in the target: 'resolver() + addend'
+ */
+struct Deferred {
+    vaddr : u64,
+    resolver : u64,
+    addend : i64
+}
+
+/*
+ * Loads the library at 'libpath' into the target without the dynamic
+ * loader: the PT_LOAD segments are laid out in a private mapping,
+ * relocated, protected, and their initializers are run. Imports are
+ * resolved against the modules already mapped in the target, so the
+ * dependencies of the library must be loaded there. Only x86_64
+ * libraries without thread-local storage are supported, and the
+ * library does not appear in the loader's link map (it can't be
+ * found by dlopen/dlsym nor unloaded by dlclose)
+ */
+pub fn map_library(remote : &mut RemoteProcess, libpath : &str, verbose : bool) -> Result<Mapping> {
+    return map_file(remote, libpath, verbose).map_err(|e| e.in_file(libpath));
+}
+
+fn map_file(remote : &mut RemoteProcess, libpath : &str, verbose : bool) -> Result<Mapping> {
+    let file = File::open(libpath).map_err(|e| Error::io(libpath, e))?;
+    let ehdr = read_ehdr(&file)?;
+    if ehdr.get_class() != ELFCLASS64 || ehdr.get_machine() != EM_X86_64 {
+        return Err(Error::elf("The manual mapper only supports x86_64 libraries"));
+    }
+    if ehdr.get_type() != ET_DYN {
+        return Err(Error::elf("The file is not a shared object"));
+    }
+
+    let mut loads : Vec<Segment> = vec![];
+    let mut tls = false;
+    ehdr.enum_segments(&file, |segment : &Segment| -> bool {
+        match segment.p_type {
+            PT_LOAD => loads.push(segment.clone()),
+            PT_TLS => tls = true,
+            _ => {  }
+        }
+        return true;
+    }).ok_or(Error::elf("Unable to read program headers from ELF file"))?;
+
+    if tls {
+        return Err(Error::elf("Libraries with thread-local storage can't be manually mapped"));
+    }
+    let first = loads.iter().map(|s| s.vaddr).min().ok_or(Error::elf("The file has no loadable segments"))? & !(PAGE_SIZE - 1);
+    let end = align_up(loads.iter().map(|s| s.vaddr + s.memsz).max().unwrap_or(first), PAGE_SIZE);
+    let size = end - first;
+
+    // The image is built here and written to the target in one go
+    let mut image : Vec<u8> = vec![0; size as usize];
+    for segment in &loads {
+        let start = (segment.vaddr - first) as usize;
+        file.read_exact_at(&mut image[start..start + segment.filesz as usize], segment.offset)
+            .map_err(|_| Error::elf("Unable to read segment from ELF file"))?;
+    }
+
+    let mapping = remote.alloc(size)?;
+    let base = mapping.wrapping_sub(first);
+    log!(verbose, "Mapping info:");
+    log!(verbose, "\tAddress: {:#x}", mapping);
+    log!(verbose, "\tSize: {:#x}", size);
+    log!(verbose, "\tBase: {:#x}", base);
+
+    let loaded = load_image(remote, &file, &loads, &mut image, first, base, libpath, verbose);
+    let (relocations, imports, deferred) = match loaded {
+        Ok(counts) => counts,
+        Err(e) => {
+            let _ = remote.munmap(mapping, size);
+            return Err(e);
+        }
+    };
+
+    // Indirect functions can only be resolved once the image is runnable.
+    // A library that fails here is not left mapped in the target either
+    let initialized = resolve_indirect(remote, &deferred, base)
+        .and_then(|_| run_initializers(remote, &file, &image, first, base, verbose));
+    let initializers = match initialized {
+        Ok(count) => count,
+        Err(e) => {
+            log!(verbose, "Unmapping {:#x} after a failed initialization", mapping);
+            let _ = remote.munmap(mapping, size);
+            return Err(e);
+        }
+    };
+
+    return Ok(Mapping {
+        pid: remote.pid(),
+        libpath: String::from(libpath),
+        base,
+        size,
+        relocations,
+        imports,
+        initializers,
+        entry_result: None
+    });
+}
+
+/*
+ * Address of the function or object 'symbol' exported
+ * by a manually mapped library
+ */
+pub fn find_export(mapping : &Mapping, symbol : &str) -> Result<u64> {
+    let file = File::open(&mapping.libpath).map_err(|e| Error::io(&mapping.libpath, e))?;
+    let mut addr : Option<u64> = None;
+    enum_symbols_in(&file, &[SymbolTable::Dynsym], |_, sym : &Symbol| -> bool {
+        if sym.is_defined() && !sym.hidden && sym.bind() != STB_LOCAL && sym.name == symbol {
+            addr = Some(mapping.base + sym.value);
+            return false;
+        }
+        return true;
+    }).map_err(|e| e.in_file(&mapping.libpath))?;
+
+    return addr.ok_or_else(|| Error::SymbolNotFound { symbol: String::from(symbol), path: mapping.libpath.clone() });
+}
+
+/*
+ * Relocates the image, writes it to the mapping and applies the
+ * protections of the segments. Returns the amount of relocations
+ * and imports, and the words that depend on indirect functions
+ */
+#[allow(clippy::too_many_arguments)]
+fn load_image(remote : &mut RemoteProcess, file : &File, loads : &[Segment], image : &mut [u8], first : u64, base : u64, libpath : &str, verbose : bool) -> Result<(usize, usize, Vec<Deferred>)> {
+    let mut symbols : Vec<Symbol> = vec![];
+    enum_symbols_in(file, &[SymbolTable::Dynsym], |_, symbol : &Symbol| -> bool {
+        symbols.push(symbol.clone());
+        return true;
+    })?;
+
+    let mut relocations : Vec<Relocation> = vec![];
+    enum_relocations(file, |relocation : &Relocation| -> bool {
+        relocations.push(relocation.clone());
+        return true;
+    })?;
+
+    // The modules of the target are only indexed if something is imported
+    let mut scope : Option<HashMap<String, (Resolved, u8)>> = None;
+    let mut resolved : HashMap<u32, Resolved> = HashMap::new();
+    let mut deferred : Vec<Deferred> = vec![];
+    for relocation in &relocations {
+        if relocation.offset < first || relocation.offset + 8 > first + image.len() as u64 {
+            return Err(Error::elf("Relocation outside of the loadable segments"));
+        }
+        let slot = (relocation.offset - first) as usize;
+
+        let symbol = match relocation.r_type {
+            R_X86_64_64 | R_X86_64_PC32 | R_X86_64_GLOB_DAT | R_X86_64_JUMP_SLOT if relocation.sym != 0 => {
+                if let Some(symbol) = resolved.get(&relocation.sym) {
+                    *symbol
+                } else {
+                    let sym = symbols.get(relocation.sym as usize).ok_or(Error::elf("Invalid relocation symbol"))?;
+                    let symbol = if sym.is_defined() {
+                        match sym.sym_type() {
+                            STT_GNU_IFUNC => Resolved::Ifunc(base + sym.value),
+                            _ => Resolved::Addr(base + sym.value)
+                        }
+                    } else {
+                        if scope.is_none() {
+                            scope = Some(import_scope(remote, verbose)?);
+                        }
+                        match scope.as_ref().and_then(|s| s.get(&sym.name)) {
+                            Some((symbol, _)) => *symbol,
+                            None if sym.bind() == STB_WEAK => Resolved::Addr(0),
+                            None => return Err(Error::SymbolNotFound { symbol: sym.name.clone(), path: String::from(libpath) })
+                        }
+                    };
+                    resolved.insert(relocation.sym, symbol);
+                    symbol
+                }
+            },
+            _ => Resolved::Addr(0)
+        };
+
+        let value = match (relocation.r_type, symbol) {
+            (R_X86_64_NONE, _) => continue,
+            (R_X86_64_RELATIVE, _) => base.wrapping_add(relocation.addend as u64),
+            (R_X86_64_64 | R_X86_64_GLOB_DAT | R_X86_64_JUMP_SLOT, Resolved::Addr(addr)) => addr.wrapping_add(relocation.addend as u64),
+            (R_X86_64_64 | R_X86_64_GLOB_DAT | R_X86_64_JUMP_SLOT, Resolved::Ifunc(resolver)) => {
+                deferred.push(Deferred { vaddr: relocation.offset, resolver, addend: relocation.addend });
+                continue;
+            },
+            (R_X86_64_IRELATIVE, _) => {
+                deferred.push(Deferred { vaddr: relocation.offset, resolver: base.wrapping_add(relocation.addend as u64), addend: 0 });
+                continue;
+            },
+            (R_X86_64_PC32, Resolved::Addr(addr)) => {
+                let value = addr.wrapping_add(relocation.addend as u64).wrapping_sub(base + relocation.offset) as i64;
+                let value = i32::try_from(value).map_err(|_| Error::elf("Relocation overflow"))?;
+                image[slot..slot + 4].copy_from_slice(&value.to_le_bytes());
+                continue;
+            },
+            (R_X86_64_DTPMOD64 | R_X86_64_DTPOFF64 | R_X86_64_TPOFF64, _) => {
+                return Err(Error::elf("Libraries with thread-local storage can't be manually mapped"));
+            },
+            (r_type, _) => {
+                log!(verbose, "Unsupported relocation type: {}", r_type);
+                return Err(Error::elf("Unsupported relocation type"));
+            }
+        };
+
+        image[slot..slot + 8].copy_from_slice(&value.to_le_bytes());
+    }
+
+    let imports = resolved.iter().filter(|(index, _)| !symbols[**index as usize].is_defined()).count();
+    log!(verbose, "Applied {} relocations ({} imported symbols)", relocations.len(), imports);
+
+    remote.write_memory(base + first, image)?;
+
+    // Pages shared by two segments get the permissions of both
+    let mut pages : Vec<ProtFlags> = vec![ProtFlags::PROT_NONE; image.len() / PAGE_SIZE as usize];
+    for segment in loads {
+        let mut prot = ProtFlags::PROT_NONE;
+        if segment.flags & PF_R != 0 { prot |= ProtFlags::PROT_READ; }
+        if segment.flags & PF_W != 0 { prot |= ProtFlags::PROT_WRITE; }
+        if segment.flags & PF_X != 0 { prot |= ProtFlags::PROT_EXEC; }
+
+        let start = ((segment.vaddr - first) / PAGE_SIZE) as usize;
+        let end = (align_up(segment.vaddr + segment.memsz - first, PAGE_SIZE) / PAGE_SIZE) as usize;
+        for page in &mut pages[start..end] {
+            *page |= prot;
+        }
+    }
+
+    let mut start = 0;
+    while start < pages.len() {
+        let count = pages[start..].iter().take_while(|prot| **prot == pages[start]).count();
+        if pages[start] != ProtFlags::PROT_READ | ProtFlags::PROT_WRITE {
+            remote.mprotect(base + first + start as u64 * PAGE_SIZE, count as u64 * PAGE_SIZE, pages[start])?;
+        }
+        start += count;
+    }
+
+    return Ok((relocations.len(), imports, deferred));
+}
+
+/*
+ * Indexes the dynamic symbols exported by the modules mapped in the
+ * target, by name. The first definition wins, like with the global
+ * lookup scope of the loader, except that strong definitions take
+ * precedence over weak ones
+ */
+fn import_scope(remote : &RemoteProcess, verbose : bool) -> Result<HashMap<String, (Resolved, u8)>> {
+    let pid = remote.pid();
+    let mut modules : Vec<MapsEntry> = vec![];
+    enum_maps(pid, |entry : MapsEntry| {
+        if entry.is_file() && !modules.iter().any(|m| m.path == entry.path) {
+            modules.push(entry);
+        }
+        return true;
+    })?;
+
+    let mut scope : HashMap<String, (Resolved, u8)> = HashMap::new();
+    for module in &modules {
+        // Not every mapped file is an ELF module (e.g. locale archives)
+        let file = match File::open(root_path(pid, &module.path)) {
+            Ok(f) => f,
+            Err(_) => continue
+        };
+        let ehdr = match read_ehdr(&file) {
+            Ok(e) => e,
+            Err(_) => continue
+        };
+        let bias = if ehdr.get_type() == ET_EXEC { 0 } else { module.base };
+
+        let result = enum_symbols_in(&file, &[SymbolTable::Dynsym], |_, symbol : &Symbol| -> bool {
+            if !symbol.is_defined() || symbol.hidden || symbol.bind() == STB_LOCAL {
+                return true;
+            }
+
+            let resolved = match symbol.sym_type() {
+                STT_FUNC | STT_OBJECT | STT_NOTYPE => Resolved::Addr(bias + symbol.value),
+                STT_GNU_IFUNC => Resolved::Ifunc(bias + symbol.value),
+                _ => return true
+            };
+
+            match scope.get(&symbol.name) {
+                Some((_, bind)) if *bind != STB_WEAK || symbol.bind() == STB_WEAK => {  },
+                _ => { scope.insert(symbol.name.clone(), (resolved, symbol.bind())); }
+            }
+            return true;
+        });
+        if result.is_ok() {
+            log!(verbose, "Import scope: {} ({:#x})", module.path, module.base);
+        }
+    }
+
+    return Ok(scope);
+}
+
+/*
+ * Calls the resolvers of the IRELATIVE and IFUNC relocations
+ * and writes the addresses they return
+ */
+fn resolve_indirect(remote : &mut RemoteProcess, deferred : &[Deferred], base : u64) -> Result<()> {
+    for fixup in deferred {
+        let value = remote.call(fixup.resolver, &[])?.wrapping_add(fixup.addend as u64);
+        remote.write_memory(base + fixup.vaddr, &value.to_le_bytes())?;
+    }
+    return Ok(());
+}
+
+/*
+ * Runs DT_INIT and then the DT_INIT_ARRAY functions of the image.
+ * The arguments of the process are not known here, so they get an
+ * argc of 0 and null argv/envp
+ */
+fn run_initializers(remote : &mut RemoteProcess, file : &File, image : &[u8], first : u64, base : u64, verbose : bool) -> Result<usize> {
+    let mut init : Option<u64> = None;
+    let mut init_array : Option<u64> = None;
+    let mut init_arraysz : u64 = 0;
+    enum_dynamic(file, |tag : i64, value : u64| -> bool {
+        match tag {
+            DT_INIT => init = Some(value),
+            DT_INIT_ARRAY => init_array = Some(value),
+            DT_INIT_ARRAYSZ => init_arraysz = value,
+            _ => {  }
+        }
+        return true;
+    })?;
+
+    let mut functions : Vec<u64> = vec![];
+    if let Some(init) = init {
+        functions.push(base + init);
+    }
+    if let Some(init_array) = init_array {
+        if init_array < first || init_array + init_arraysz > first + image.len() as u64 {
+            return Err(Error::elf("DT_INIT_ARRAY is outside of the loadable segments"));
+        }
+
+        // The entries were relocated in the image
+        let start = (init_array - first) as usize;
+        for entry in image[start..start + init_arraysz as usize].chunks_exact(8) {
+            let addr = u64::from_le_bytes(entry.try_into().unwrap());
+            if addr != 0 && addr != u64::MAX {
+                functions.push(addr);
+            }
+        }
+    }
+
+    for function in &functions {
+        log!(verbose, "Running initializer at {:#x}", function);
+        remote.call(*function, &[RemoteArg::Int(0), RemoteArg::Ptr(0), RemoteArg::Ptr(0)])?;
+    }
+
+    return Ok(functions.len());
+}
@@ -0,0 +1,253 @@
+use std::fs::{File, OpenOptions};
+use std::os::unix::fs::FileExt;
+use std::mem::size_of;
+use core::ffi::c_void;
+use nix::libc::c_long;
+
+use nix::unistd::Pid;
+use nix::errno::Errno;
+use nix::sys::ptrace;
+use nix::sys::ptrace::AddressType;
+use nix::sys::uio::{process_vm_readv, process_vm_writev, IoVec, RemoteIoVec};
+
+use crate::error::{Error, Phase, Result};
+
+/*
+ * Ways of accessing the memory of another process, in order of
+ * preference. 'ProcMem' can write to read-only pages, and 'Ptrace'
+ * requires the process to be attached and stopped
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryBackend {
+    ProcessVm,
+    ProcMem,
+    Ptrace
+}
+
+/* Bytes transferred by a backend, and why it stopped early */
+type Transfer = (usize, std::result::Result<(), Errno>);
+
+const BACKENDS : [MemoryBackend;3] = [MemoryBackend::ProcessVm, MemoryBackend::ProcMem, MemoryBackend::Ptrace];
+
+/*
+ * Byte-exact reader/writer for the memory of process 'pid'.
+ * Each transfer is attempted with every backend in order,
+ * continuing from where the previous one stopped
+ */
+#[derive(Debug, Clone, Copy)]
+pub struct RemoteMemory {
+    pid : Pid
+}
+
+impl RemoteMemory {
+    pub fn new(pid : i32) -> Self {
+        return RemoteMemory { pid: Pid::from_raw(pid) };
+    }
+
+    pub fn read(&self, addr : u64, size : usize) -> Result<Vec<u8>> {
+        let mut buf = vec![0;size];
+        self.read_into(addr, &mut buf)?;
+        return Ok(buf);
+    }
+
+    pub fn read_into(&self, addr : u64, buf : &mut [u8]) -> Result<()> {
+        let mut done = 0;
+        let mut errno = Errno::EFAULT;
+        for backend in BACKENDS {
+            if done == buf.len() {
+                break;
+            }
+
+            let (count, result) = match backend {
+                MemoryBackend::ProcessVm => self.vm_read(addr + done as u64, &mut buf[done..]),
+                MemoryBackend::ProcMem => self.procmem_read(addr + done as u64, &mut buf[done..]),
+                MemoryBackend::Ptrace => self.ptrace_read(addr + done as u64, &mut buf[done..])
+            };
+            done += count;
+            if let Err(e) = result {
+                errno = e;
+            }
+        }
+
+        if done < buf.len() {
+            return Err(Error::Memory { pid: self.pid.as_raw(), addr: addr + done as u64, phase: Phase::ReadMemory, errno });
+        }
+
+        return Ok(());
+    }
+
+    /*
+     * Reads a null-terminated string of at most 'max_len' bytes.
+     * Reads never cross a page boundary past the terminator, so
+     * a string at the end of a mapping can be read
+     */
+    pub fn read_cstr(&self, addr : u64, max_len : usize) -> Result<String> {
+        const PAGE_SIZE : u64 = 0x1000;
+
+        let mut buf : Vec<u8> = vec![];
+        while buf.len() < max_len {
+            let curaddr = addr + buf.len() as u64;
+            let count = ((PAGE_SIZE - (curaddr % PAGE_SIZE)) as usize).min(max_len - buf.len());
+            let chunk = self.read(curaddr, count)?;
+            if let Some(end) = chunk.iter().position(|b| *b == 0) {
+                buf.extend(&chunk[..end]);
+                break;
+            }
+            buf.extend(chunk);
+        }
+
+        return Ok(String::from_utf8_lossy(&buf).into_owned());
+    }
+
+    pub fn write(&self, addr : u64, buf : &[u8]) -> Result<()> {
+        let mut done = 0;
+        let mut errno = Errno::EFAULT;
+        for backend in BACKENDS {
+            if done == buf.len() {
+                break;
+            }
+
+            let (count, result) = match backend {
+                MemoryBackend::ProcessVm => self.vm_write(addr + done as u64, &buf[done..]),
+                MemoryBackend::ProcMem => self.procmem_write(addr + done as u64, &buf[done..]),
+                MemoryBackend::Ptrace => self.ptrace_write(addr + done as u64, &buf[done..])
+            };
+            done += count;
+            if let Err(e) = result {
+                errno = e;
+            }
+        }
+
+        if done < buf.len() {
+            return Err(Error::Memory { pid: self.pid.as_raw(), addr: addr + done as u64, phase: Phase::WriteMemory, errno });
+        }
+
+        return Ok(());
+    }
+
+    fn vm_read(&self, addr : u64, buf : &mut [u8]) -> Transfer {
+        let mut done = 0;
+        while done < buf.len() {
+            let remote = [RemoteIoVec { base: addr as usize + done, len: buf.len() - done }];
+            let local = [IoVec::from_mut_slice(&mut buf[done..])];
+            match process_vm_readv(self.pid, &local, &remote) {
+                Ok(0) => return (done, Err(Errno::EFAULT)),
+                Ok(count) => done += count,
+                Err(e) => return (done, Err(e))
+            }
+        }
+
+        return (done, Ok(()));
+    }
+
+    fn vm_write(&self, addr : u64, buf : &[u8]) -> Transfer {
+        let mut done = 0;
+        while done < buf.len() {
+            let remote = [RemoteIoVec { base: addr as usize + done, len: buf.len() - done }];
+            let local = [IoVec::from_slice(&buf[done..])];
+            match process_vm_writev(self.pid, &local, &remote) {
+                Ok(0) => return (done, Err(Errno::EFAULT)),
+                Ok(count) => done += count,
+                Err(e) => return (done, Err(e))
+            }
+        }
+
+        return (done, Ok(()));
+    }
+
+    fn procmem_open(&self, write : bool) -> std::result::Result<File, Errno> {
+        return OpenOptions::new()
+            .read(true)
+            .write(write)
+            .open(format!("/proc/{}/mem", self.pid))
+            .map_err(|e| Errno::from_i32(e.raw_os_error().unwrap_or(0)));
+    }
+
+    fn procmem_read(&self, addr : u64, buf : &mut [u8]) -> Transfer {
+        let file = match self.procmem_open(false) {
+            Ok(f) => f,
+            Err(e) => return (0, Err(e))
+        };
+
+        let mut done = 0;
+        while done < buf.len() {
+            match file.read_at(&mut buf[done..], addr + done as u64) {
+                Ok(0) => return (done, Err(Errno::EFAULT)),
+                Ok(count) => done += count,
+                Err(e) => return (done, Err(Errno::from_i32(e.raw_os_error().unwrap_or(0))))
+            }
+        }
+
+        return (done, Ok(()));
+    }
+
+    fn procmem_write(&self, addr : u64, buf : &[u8]) -> Transfer {
+        let file = match self.procmem_open(true) {
+            Ok(f) => f,
+            Err(e) => return (0, Err(e))
+        };
+
+        let mut done = 0;
+        while done < buf.len() {
+            match file.write_at(&buf[done..], addr + done as u64) {
+                Ok(0) => return (done, Err(Errno::EFAULT)),
+                Ok(count) => done += count,
+                Err(e) => return (done, Err(Errno::from_i32(e.raw_os_error().unwrap_or(0))))
+            }
+        }
+
+        return (done, Ok(()));
+    }
+
+    fn ptrace_read(&self, addr : u64, buf : &mut [u8]) -> Transfer {
+        const DATSIZ : u64 = size_of::<c_long>() as u64;
+
+        let mut done = 0;
+        while done < buf.len() {
+            // Only access whole aligned words, so that no extra page is touched
+            let curaddr = addr + done as u64;
+            let wordaddr = curaddr & !(DATSIZ - 1);
+            let skip = (curaddr - wordaddr) as usize;
+            let count = (DATSIZ as usize - skip).min(buf.len() - done);
+
+            let data = match ptrace::read(self.pid, wordaddr as AddressType) {
+                Ok(d) => d.to_ne_bytes(),
+                Err(e) => return (done, Err(e))
+            };
+            buf[done..done + count].copy_from_slice(&data[skip..skip + count]);
+            done += count;
+        }
+
+        return (done, Ok(()));
+    }
+
+    fn ptrace_write(&self, addr : u64, buf : &[u8]) -> Transfer {
+        const DATSIZ : u64 = size_of::<c_long>() as u64;
+
+        let mut done = 0;
+        while done < buf.len() {
+            let curaddr = addr + done as u64;
+            let wordaddr = curaddr & !(DATSIZ - 1);
+            let skip = (curaddr - wordaddr) as usize;
+            let count = (DATSIZ as usize - skip).min(buf.len() - done);
+
+            // Partial words are merged with the current contents
+            let mut data = [0;DATSIZ as usize];
+            if count != DATSIZ as usize {
+                data = match ptrace::read(self.pid, wordaddr as AddressType) {
+                    Ok(d) => d.to_ne_bytes(),
+                    Err(e) => return (done, Err(e))
+                };
+            }
+            data[skip..skip + count].copy_from_slice(&buf[done..done + count]);
+
+            let word = c_long::from_ne_bytes(data) as *mut c_void;
+            if let Err(e) = unsafe { ptrace::write(self.pid, wordaddr as AddressType, word) } {
+                return (done, Err(e));
+            }
+            done += count;
+        }
+
+        return (done, Ok(()));
+    }
+}
@@ -1,20 +1,114 @@
 use std::path::Path;
-use std::fs::{read_dir, File, read_link};
-use std::io::{BufRead, BufReader};
-use regex::Regex;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+use std::fs::{self, read_dir, File, read_link};
+use std::os::unix::fs::MetadataExt;
+use std::io::{self, BufRead, BufReader, Read};
 use nix::sys::mman::ProtFlags;
+use nix::sys::stat::makedev;
+use crate::error::{Error, Result};
 
-#[derive(Debug)]
+/*
+ * Interval between two scans of '/proc' while waiting. It doubles
+ * after each scan, up to a maximum kept low so that a process that
+ * was just started is caught before it runs for long
+ */
+const POLL_INTERVAL : Duration = Duration::from_millis(20);
+const MAX_POLL_INTERVAL : Duration = Duration::from_millis(50);
+
+#[derive(Debug, Clone)]
 pub struct MapsEntry {
     pub base : u64,
     pub end : u64,
     pub flags : ProtFlags,
-    pub path : String
+    /* MAP_SHARED mapping ('s'), as opposed to MAP_PRIVATE ('p') */
+    pub shared : bool,
+    /* Offset of the mapping in the file */
+    pub offset : u64,
+    /*
+     * Device and inode of the file, 0 if there is none. The device is
+     * the one of the superblock, encoded like 'st_dev', but it is not
+     * always what 'stat' reports (btrfs subvolumes, overlayfs)
+     */
+    pub dev : u64,
+    pub inode : u64,
+    /*
+     * Path of the file, name of a pseudo mapping ('[heap]', '[stack]',
+     * '[vdso]'...), or empty for anonymous memory
+     */
+    pub path : String,
+    /* The file was deleted (or never had a path, like memfds) */
+    pub deleted : bool
+}
+
+impl MapsEntry {
+    /* Backed by a file (including deleted ones) */
+    pub fn is_file(&self) -> bool {
+        return self.inode != 0;
+    }
+
+    pub fn is_anonymous(&self) -> bool {
+        return self.path.is_empty();
+    }
+
+    /* Special mapping of the kernel, like '[heap]' or '[vdso]' */
+    pub fn is_pseudo(&self) -> bool {
+        return !self.is_file() && self.path.starts_with('[');
+    }
+
+    /* Permissions in the 'rwxp' notation of the maps file */
+    pub fn perms(&self) -> String {
+        let perm = |flag : ProtFlags, c : char| if self.flags.contains(flag) { c } else { '-' };
+        let sharing = if self.shared { 's' } else { 'p' };
+        return [perm(ProtFlags::PROT_READ, 'r'), perm(ProtFlags::PROT_WRITE, 'w'), perm(ProtFlags::PROT_EXEC, 'x'), sharing].iter().collect();
+    }
+}
+
+/*
+ * Every mapping of one file in a process, in address order
+ */
+#[derive(Debug, Clone)]
+pub struct Module {
+    pub path : String,
+    pub dev : u64,
+    pub inode : u64,
+    pub segments : Vec<MapsEntry>
+}
+
+impl Module {
+    pub fn base(&self) -> u64 {
+        return self.segments.first().map(|s| s.base).unwrap_or(0);
+    }
+
+    pub fn end(&self) -> u64 {
+        return self.segments.last().map(|s| s.end).unwrap_or(0);
+    }
+
+    pub fn size(&self) -> u64 {
+        return self.end() - self.base();
+    }
+}
+
+/*
+ * Maps a failed access to '/proc/<pid>/...' into an error,
+ * reporting a missing process instead of a missing file
+ */
+fn procfs_error(pid : i32, path : &str, e : io::Error) -> Error {
+    if e.kind() == io::ErrorKind::NotFound {
+        return Error::NoSuchProcess(pid);
+    }
+
+    return Error::io(path, e);
 }
 
-pub fn enum_pids<F>(mut callback : F) -> Option<()> where F : FnMut(i32) -> bool {
+fn open_procfs(pid : i32, name : &str) -> Result<File> {
+    let path = format!("/proc/{}/{}", pid, name);
+    return File::open(&path).map_err(|e| procfs_error(pid, &path, e));
+}
+
+pub fn enum_pids<F>(mut callback : F) -> Result<()> where F : FnMut(i32) -> bool {
     let procfs = Path::new("/proc");
-    let dir = read_dir(procfs).ok()?;
+    let dir = read_dir(procfs).map_err(|e| Error::io("/proc", e))?;
 
     for entry in dir {
         let entry = match entry {
@@ -42,103 +136,474 @@ pub fn enum_pids<F>(mut callback : F) -> Option<()> where F : FnMut(i32) -> bool
         }
     }
 
-    return Some(());
+    return Ok(());
+}
+
+/*
+ * Enumerates the thread IDs of the process 'pid'
+ */
+pub fn enum_tasks<F>(pid : i32, mut callback : F) -> Result<()> where F : FnMut(i32) -> bool {
+    let path = format!("/proc/{}/task", pid);
+    let dir = read_dir(&path).map_err(|e| procfs_error(pid, &path, e))?;
+
+    for entry in dir {
+        let entry = match entry {
+            Ok(e) => e,
+            _ => continue
+        };
+
+        let tid : i32 = match entry.file_name().to_str().map(|s| s.parse()) {
+            Some(Ok(t)) => t,
+            _ => continue
+        };
+
+        if !callback(tid) {
+            break;
+        }
+    }
+
+    return Ok(());
+}
+
+/*
+ * Parses a line of a maps file, which has the form
+ * 'start-end perms offset major:minor inode [path]'
+ */
+fn parse_maps_line(line : &str) -> Option<MapsEntry> {
+    let mut fields : [&str;5] = [""; 5];
+    let mut rest = line;
+    for field in fields.iter_mut() {
+        rest = rest.trim_start();
+        let len = rest.find(char::is_whitespace).unwrap_or(rest.len());
+        *field = &rest[..len];
+        rest = &rest[len..];
+    }
+
+    let (base, end) = fields[0].split_once('-')?;
+    let perms = fields[1].as_bytes();
+    if perms.len() != 4 {
+        return None;
+    }
+
+    let mut flags = ProtFlags::empty();
+    for (c, flag) in perms.iter().zip([ProtFlags::PROT_READ, ProtFlags::PROT_WRITE, ProtFlags::PROT_EXEC]) {
+        if *c != b'-' {
+            flags |= flag;
+        }
+    }
+
+    let (major, minor) = fields[3].split_once(':')?;
+
+    // The path is the rest of the line, and may contain spaces
+    let path = rest.trim_start();
+    let (path, deleted) = match path.strip_suffix(" (deleted)") {
+        Some(p) => (p, true),
+        None => (path, false)
+    };
+
+    return Some(MapsEntry {
+        base: u64::from_str_radix(base, 16).ok()?,
+        end: u64::from_str_radix(end, 16).ok()?,
+        flags,
+        shared: perms[3] == b's',
+        offset: u64::from_str_radix(fields[2], 16).ok()?,
+        dev: makedev(u64::from_str_radix(major, 16).ok()?, u64::from_str_radix(minor, 16).ok()?),
+        inode: fields[4].parse().ok()?,
+        path: String::from(path),
+        deleted
+    });
 }
 
-pub fn enum_maps<F>(pid : i32, mut callback : F) -> Option<()>
+/*
+ * Enumerates every mapping of the process: file mappings as well
+ * as anonymous memory and pseudo mappings ('[heap]', '[stack]'...)
+ */
+pub fn enum_maps<F>(pid : i32, mut callback : F) -> Result<()>
 where F : FnMut(MapsEntry) -> bool {
-    let maps_file = File::open(format!("/proc/{}/maps", pid)).ok()?;
+    let maps_path = format!("/proc/{}/maps", pid);
+    let maps_file = open_procfs(pid, "maps")?;
     let reader = BufReader::new(maps_file);
-    let re = Regex::new(r"([0-9a-f]+)-([0-9a-f]+)\s+([rwxp\-]+).*\s+(/.*)").ok()?;
 
     for line in reader.lines() {
-        let line = line.ok()?;
-        let caps = match re.captures(line.as_str()) {
-            Some(c) => c,
-            _ => continue
+        let line = line.map_err(|e| procfs_error(pid, &maps_path, e))?;
+        let entry = match parse_maps_line(&line) {
+            Some(e) => e,
+            None => return Err(Error::io(&maps_path, io::Error::new(io::ErrorKind::InvalidData, format!("malformed line \"{}\"", line))))
         };
-        
-        let base_addr = u64::from_str_radix(&caps[1], 16).unwrap();
-        let end_addr = u64::from_str_radix(&caps[2], 16).unwrap();
-        let mut flags = ProtFlags::empty();
-        for c in String::from(&caps[3]).chars() {
-            flags = match c {
-                'r' => flags | ProtFlags::PROT_READ,
-                'w' => flags | ProtFlags::PROT_WRITE,
-                'x' => flags | ProtFlags::PROT_EXEC,
-                _ => continue
-            }
-        }
-        let path = String::from(&caps[4]);
-        let entry = MapsEntry{ base: base_addr, end: end_addr, flags: flags, path: path };
 
         if !callback(entry) {
             break;
         }
     }
 
-    return Some(());
+    return Ok(());
+}
+
+/*
+ * Groups the file mappings of the process by file (device and
+ * inode), in the order of their first mapping
+ */
+pub fn enum_modules(pid : i32) -> Result<Vec<Module>> {
+    let mut modules : Vec<Module> = vec![];
+    enum_maps(pid, |entry : MapsEntry| {
+        if !entry.is_file() {
+            return true;
+        }
+
+        match modules.iter_mut().find(|m| m.dev == entry.dev && m.inode == entry.inode) {
+            Some(module) => module.segments.push(entry),
+            None => modules.push(Module {
+                path: entry.path.clone(),
+                dev: entry.dev,
+                inode: entry.inode,
+                segments: vec![entry]
+            })
+        }
+        return true;
+    })?;
+
+    return Ok(modules);
 }
 
-pub fn name_from_pid(pid : i32) -> Option<String> {
-    let mut name = None;
+/*
+ * Opens the file of a module through '/proc/<pid>/map_files', which
+ * works for files that were deleted (or never had a path, like
+ * memfds) and for processes in another mount namespace
+ */
+pub fn open_module(pid : i32, module : &Module) -> Result<File> {
+    let path = map_files_path(pid, module);
+    return File::open(&path).map_err(|e| procfs_error(pid, &path, e));
+}
+
+/*
+ * Device and inode of the file of a module, as 'stat' reports them
+ * (unlike the ones of the maps), through '/proc/<pid>/map_files'
+ */
+pub fn module_file_id(pid : i32, module : &Module) -> Result<(u64, u64)> {
+    let path = map_files_path(pid, module);
+    let metadata = fs::metadata(&path).map_err(|e| procfs_error(pid, &path, e))?;
+    return Ok((metadata.dev(), metadata.ino()));
+}
+
+fn map_files_path(pid : i32, module : &Module) -> String {
+    return format!("/proc/{}/map_files/{:x}-{:x}", pid, module.base(), module.segments[0].end);
+}
+
+/*
+ * Reads the auxiliary vector of the process as (AT_*, value)
+ * pairs. 'word_size' is the pointer size of the process
+ */
+pub fn read_auxv(pid : i32, word_size : usize) -> Result<Vec<(u64, u64)>> {
+    let mut auxv_file = open_procfs(pid, "auxv")?;
+    let mut data : Vec<u8> = vec![];
+    auxv_file.read_to_end(&mut data).map_err(|e| procfs_error(pid, &format!("/proc/{}/auxv", pid), e))?;
+
+    let mut entries : Vec<(u64, u64)> = vec![];
+    for pair in data.chunks_exact(word_size * 2) {
+        let mut words = [0u64;2];
+        for (word, bytes) in words.iter_mut().zip(pair.chunks_exact(word_size)) {
+            let mut buf = [0u8;8];
+            buf[..word_size].copy_from_slice(bytes);
+            *word = u64::from_le_bytes(buf);
+        }
+
+        if words[0] == 0 { // AT_NULL
+            break;
+        }
+        entries.push((words[0], words[1]));
+    }
+
+    return Ok(entries);
+}
+
+pub fn name_from_pid(pid : i32) -> Result<String> {
     let status_path = format!("/proc/{}/status", pid);
-    let status_file = File::open(status_path).ok()?;
+    let status_file = open_procfs(pid, "status")?;
     let reader = BufReader::new(status_file);
 
     for line in reader.lines() {
-        let line = line.ok()?;
+        let line = line.map_err(|e| procfs_error(pid, &status_path, e))?;
         if !line.starts_with("Name:") {
             continue;
         }
 
         if let Some(index) = line.find('\t') {
-            name = Some(line[index + 1 ..].to_string());
+            return Ok(line[index + 1 ..].to_string());
         }
 
         break;
     }
 
-    return name;
+    return Err(Error::io(&status_path, io::Error::new(io::ErrorKind::InvalidData, "missing 'Name' field")));
+}
+
+/*
+ * Start time of the process, in clock ticks since boot. Along with
+ * the PID, it identifies the process even if the PID gets reused
+ */
+pub fn start_time(pid : i32) -> Result<u64> {
+    let stat_path = format!("/proc/{}/stat", pid);
+    let mut stat = String::new();
+    open_procfs(pid, "stat")?.read_to_string(&mut stat).map_err(|e| procfs_error(pid, &stat_path, e))?;
+
+    // The name (2nd field) may contain spaces and parentheses, so the
+    // fields are counted from the last ')': 'starttime' is the 22nd
+    return stat.rfind(')')
+        .and_then(|index| stat[index + 1 ..].split_whitespace().nth(19))
+        .and_then(|field| field.parse::<u64>().ok())
+        .ok_or_else(|| Error::io(&stat_path, io::Error::new(io::ErrorKind::InvalidData, "missing 'starttime' field")));
 }
 
-pub fn pid_from_name(name : &String) -> Option<i32> {
+pub fn pid_from_name(name : &str) -> Result<i32> {
     let mut pid = None;
-    
+
     enum_pids(|curpid : i32| {
-        if let Some(curname) = name_from_pid(curpid) {
-            if curname == *name {
+        if let Ok(curname) = name_from_pid(curpid) {
+            if curname == name {
                 pid = Some(curpid);
                 return false;
             }
         }
 
         return true;
-    });
+    })?;
+
+    return pid.ok_or_else(|| Error::ProcessNotFound(String::from(name)));
+}
+
+/*
+ * Every process named 'name', in '/proc' order
+ */
+pub fn pids_from_name(name : &str) -> Result<Vec<i32>> {
+    let mut pids : Vec<i32> = vec![];
+
+    enum_pids(|curpid : i32| {
+        if let Ok(curname) = name_from_pid(curpid) {
+            if curname == name {
+                pids.push(curpid);
+            }
+        }
 
-    return pid;
+        return true;
+    })?;
+
+    return Ok(pids);
 }
 
-pub fn exepath_from_pid(pid : i32) -> Option<String> {
+pub fn exepath_from_pid(pid : i32) -> Result<String> {
     let sym_path = format!("/proc/{}/exe", pid);
-    let real_path = read_link(sym_path).ok()?;
-    return Some(String::from(real_path.to_str()?));
+    let real_path = read_link(&sym_path).map_err(|e| procfs_error(pid, &sym_path, e))?;
+    return Ok(real_path.to_string_lossy().to_string());
+}
+
+/*
+ * Opens the executable of the process, which works even when
+ * it lives in another mount namespace or was deleted
+ */
+pub fn open_exe(pid : i32) -> Result<File> {
+    return open_procfs(pid, "exe");
+}
+
+/*
+ * Path through which the file 'path', as seen by the process 'pid',
+ * can be reached from here. It accounts for processes running in
+ * another mount namespace (containers) or in a chroot
+ */
+pub fn root_path(pid : i32, path : &str) -> String {
+    return format!("/proc/{}/root{}", pid, path);
+}
+
+/*
+ * Whether the process 'pid' sees the same filesystem as this
+ * one: same mount namespace and same root directory
+ */
+pub fn shares_root(pid : i32) -> Result<bool> {
+    let link = |pid : Option<i32>, name : &str| -> Result<std::path::PathBuf> {
+        let path = match pid {
+            Some(pid) => format!("/proc/{}/{}", pid, name),
+            None => format!("/proc/self/{}", name)
+        };
+        return read_link(&path).map_err(|e| match pid {
+            Some(pid) => procfs_error(pid, &path, e),
+            None => Error::io(&path, e)
+        });
+    };
+
+    return Ok(link(Some(pid), "ns/mnt")? == link(None, "ns/mnt")? && link(Some(pid), "root")? == link(None, "root")?);
 }
 
-pub fn pid_from_fname(fname : &String) -> Option<i32> {
+pub fn pid_from_fname(fname : &str) -> Result<i32> {
     let mut pid = None;
-    let fname = format!("/{}", fname);
-    
+    let suffix = format!("/{}", fname);
+
     enum_pids(|curpid : i32| {
-        if let Some(curpath) = exepath_from_pid(curpid) {
-            if curpath.ends_with(&fname) {
+        if let Ok(curpath) = exepath_from_pid(curpid) {
+            if curpath.ends_with(&suffix) {
                 pid = Some(curpid);
                 return false;
             }
         }
 
         return true;
+    })?;
+
+    return pid.ok_or_else(|| Error::ProcessNotFound(String::from(fname)));
+}
+
+/*
+ * Every process whose executable path ends with '/fname'
+ */
+pub fn pids_from_fname(fname : &str) -> Result<Vec<i32>> {
+    let mut pids : Vec<i32> = vec![];
+    let suffix = format!("/{}", fname);
+
+    enum_pids(|curpid : i32| {
+        if let Ok(curpath) = exepath_from_pid(curpid) {
+            if curpath.ends_with(&suffix) {
+                pids.push(curpid);
+            }
+        }
+
+        return true;
+    })?;
+
+    return Ok(pids);
+}
+
+/*
+ * Calls 'poll' until it returns a value, sleeping between calls
+ * for longer and longer. Fails with 'Error::Timeout' (describing
+ * 'what' was awaited) once 'deadline' is reached, or waits forever
+ * without one
+ */
+pub fn wait_for<T, F>(what : &str, deadline : Option<Instant>, mut poll : F) -> Result<T>
+where F : FnMut() -> Result<Option<T>> {
+    let mut interval = POLL_INTERVAL;
+    loop {
+        if let Some(value) = poll()? {
+            return Ok(value);
+        }
+
+        let mut delay = interval;
+        if let Some(deadline) = deadline {
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(Error::Timeout(String::from(what)));
+            }
+            delay = delay.min(deadline - now);
+        }
+
+        sleep(delay);
+        interval = (interval * 2).min(MAX_POLL_INTERVAL);
+    }
+}
+
+/*
+ * Like 'pid_from_name', but waits for a matching process to appear
+ */
+pub fn wait_pid_from_name(name : &str, deadline : Option<Instant>) -> Result<i32> {
+    return wait_for(&format!("a process named {}", name), deadline, || match pid_from_name(name) {
+        Ok(pid) => Ok(Some(pid)),
+        Err(Error::ProcessNotFound(_)) => Ok(None),
+        Err(e) => Err(e)
     });
+}
+
+/*
+ * Like 'pid_from_fname', but waits for a matching process to appear
+ */
+pub fn wait_pid_from_fname(fname : &str, deadline : Option<Instant>) -> Result<i32> {
+    return wait_for(&format!("a process running {}", fname), deadline, || match pid_from_fname(fname) {
+        Ok(pid) => Ok(Some(pid)),
+        Err(Error::ProcessNotFound(_)) => Ok(None),
+        Err(e) => Err(e)
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    return pid;
+    #[test]
+    fn parse_file_mapping() {
+        let entry = parse_maps_line("7f1a1f281000-7f1a1f282000 r-xp 00001000 fd:01 1835263                    /usr/lib/x86_64-linux-gnu/libc.so.6").unwrap();
+        assert_eq!(entry.base, 0x7f1a1f281000);
+        assert_eq!(entry.end, 0x7f1a1f282000);
+        assert_eq!(entry.flags, ProtFlags::PROT_READ | ProtFlags::PROT_EXEC);
+        assert!(!entry.shared);
+        assert_eq!(entry.offset, 0x1000);
+        assert_eq!(entry.dev, makedev(0xfd, 0x01));
+        assert_eq!(entry.inode, 1835263);
+        assert_eq!(entry.path, "/usr/lib/x86_64-linux-gnu/libc.so.6");
+        assert!(!entry.deleted);
+        assert!(entry.is_file());
+        assert_eq!(entry.perms(), "r-xp");
+    }
+
+    #[test]
+    fn parse_anonymous_mapping() {
+        let entry = parse_maps_line("7f7741e45000-7f7741e46000 rw-p 00000000 00:00 0 ").unwrap();
+        assert_eq!(entry.flags, ProtFlags::PROT_READ | ProtFlags::PROT_WRITE);
+        assert_eq!(entry.dev, 0);
+        assert_eq!(entry.inode, 0);
+        assert!(entry.is_anonymous());
+        assert!(!entry.is_file());
+        assert!(!entry.is_pseudo());
+
+        // Without the trailing space
+        assert!(parse_maps_line("7f7741e45000-7f7741e46000 ---p 00000000 00:00 0").unwrap().is_anonymous());
+    }
+
+    #[test]
+    fn parse_pseudo_mappings() {
+        for (line, name) in [
+            ("55c156fc2000-55c156fe3000 rw-p 00000000 00:00 0                          [heap]", "[heap]"),
+            ("7ffee5d6f000-7ffee5d71000 r-xp 00000000 00:00 0                          [vdso]", "[vdso]")
+        ] {
+            let entry = parse_maps_line(line).unwrap();
+            assert_eq!(entry.path, name);
+            assert!(entry.is_pseudo());
+            assert!(!entry.is_anonymous());
+            assert!(!entry.is_file());
+        }
+    }
+
+    #[test]
+    fn parse_path_with_spaces() {
+        let entry = parse_maps_line("7f0000000000-7f0000001000 r--p 00000000 08:02 42                         /tmp/my lib/lib test.so").unwrap();
+        assert_eq!(entry.path, "/tmp/my lib/lib test.so");
+        assert!(!entry.deleted);
+    }
+
+    #[test]
+    fn parse_deleted_file() {
+        let entry = parse_maps_line("7f0000000000-7f0000001000 r-xp 00001000 08:02 42                         /tmp/old lib.so (deleted)").unwrap();
+        assert_eq!(entry.path, "/tmp/old lib.so");
+        assert!(entry.deleted);
+        assert!(entry.is_file());
+    }
+
+    #[test]
+    fn parse_memfd() {
+        let entry = parse_maps_line("7f7741412000-7f7741413000 r--p 00000000 00:01 2055                       /memfd:libtest.so (deleted)").unwrap();
+        assert_eq!(entry.path, "/memfd:libtest.so");
+        assert_eq!(entry.dev, makedev(0, 1));
+        assert!(entry.deleted);
+        assert!(entry.is_file());
+    }
+
+    #[test]
+    fn parse_shared_mapping() {
+        let entry = parse_maps_line("7f0000000000-7f0000010000 rw-s 00000000 00:05 1234                       /dev/shm/buffer").unwrap();
+        assert!(entry.shared);
+        assert_eq!(entry.perms(), "rw-s");
+    }
+
+    #[test]
+    fn reject_malformed_lines() {
+        assert!(parse_maps_line("").is_none());
+        assert!(parse_maps_line("7f0000000000 r-xp 00000000 08:02 42 /lib.so").is_none());
+        assert!(parse_maps_line("7f0000000000-7f0000001000 r-x 00000000 08:02 42 /lib.so").is_none());
+        assert!(parse_maps_line("7f0000000000-7f0000001000 r-xp 00000000 0802 42 /lib.so").is_none());
+    }
 }
@@ -0,0 +1,770 @@
+use std::io;
+use std::process::Command;
+use std::os::unix::process::CommandExt;
+
+use nix::libc;
+use nix::unistd::Pid;
+use nix::errno::Errno;
+use nix::sys::ptrace;
+use nix::sys::signal::{kill, Signal};
+use nix::sys::wait;
+use nix::sys::wait::WaitStatus;
+use nix::sys::mman::{ProtFlags, MapFlags};
+
+use crate::proc::{open_exe, enum_tasks, enum_maps, MapsEntry};
+use crate::elf::read_ehdr;
+use crate::elf::elfdefs::ElfW_Ehdr;
+use crate::error::{Error, Phase, Result};
+use crate::mem::RemoteMemory;
+use crate::arch::{self, Arch, Regs, Sysno};
+
+pub(crate) const PAGE_SIZE : u64 = 0x1000;
+
+/* Size of the executable region that holds the call stub */
+const CODE_REGION_SIZE : u64 = PAGE_SIZE;
+
+/* Bytes written to a memfd with each 'write' system call */
+const MEMFD_CHUNK : usize = 0x10000;
+
+/* Flags of memfd_create */
+const MFD_CLOEXEC : u64 = 1;
+
+/* Bytes of an executable mapping scanned at a time for a syscall instruction */
+const SCAN_CHUNK : u64 = 0x10000;
+
+/*
+ * Argument of a remote function call. Strings and byte buffers
+ * are copied into the target and passed by address
+ */
+#[derive(Debug, Clone, Copy)]
+pub enum RemoteArg<'a> {
+    Int(u64),
+    Ptr(u64),
+    Str(&'a str),
+    Bytes(&'a [u8])
+}
+
+/*
+ * Secondary thread kept stopped while the process is manipulated,
+ * with the signal it was stopped for (if any), to be delivered
+ * when it is resumed
+ */
+struct StoppedThread {
+    tid : Pid,
+    pending_signal : Option<Signal>
+}
+
+/*
+ * A process attached with ptrace. It is detached (and the
+ * signals swallowed during the session are delivered) when
+ * 'detach' is called or when it goes out of scope
+ */
+pub struct RemoteProcess {
+    pid : Pid,
+    memory : RemoteMemory,
+    elf_class : u8,
+    arch : Box<dyn Arch>,
+    verbose : bool,
+    attached : bool,
+    pending_signals : Vec<Signal>,
+    threads : Vec<StoppedThread>,
+    syscall_insn : Option<u64>,
+    code_region : Option<u64>
+}
+
+impl RemoteProcess {
+    pub fn attach(pid : i32, verbose : bool) -> Result<Self> {
+        let (elf_class, arch) = Self::exe_arch(pid)?;
+
+        let pid = Pid::from_raw(pid);
+        log!(verbose, "Attaching to process...");
+        ptrace::seize(pid, ptrace::Options::empty()).map_err(|e| Error::ptrace(pid.as_raw(), Phase::Attach, e))?;
+        if let Err(e) = ptrace::interrupt(pid) {
+            let _ = ptrace::detach(pid, None);
+            return Err(Error::ptrace(pid.as_raw(), Phase::Interrupt, e));
+        }
+
+        // A signal may be reported before the interrupt stop
+        let mut remote = Self::traced(pid, elf_class, arch, verbose);
+        while let WaitStatus::Stopped(_, signal) = remote.wait_stopped()? {
+            log!(verbose, "Postponing signal: {}", signal);
+            remote.pending_signals.push(signal);
+            ptrace::cont(pid, None).map_err(|e| Error::ptrace(pid.as_raw(), Phase::Continue, e))?;
+        }
+        log!(verbose, "Attached to process {}", pid);
+
+        return Ok(remote);
+    }
+
+    /*
+     * Starts 'program' (searched in PATH) as a traced child. It is
+     * stopped right after execve, before the dynamic loader runs
+     */
+    pub fn launch(program : &str, args : &[String], verbose : bool) -> Result<Self> {
+        let mut command = Command::new(program);
+        command.args(args);
+        unsafe {
+            command.pre_exec(|| ptrace::traceme().map_err(|e| io::Error::from_raw_os_error(e as i32)));
+        }
+
+        log!(verbose, "Launching {} {:?}...", program, args);
+        let child = command.spawn().map_err(|e| Error::io(program, e))?;
+        let pid = Pid::from_raw(child.id() as i32);
+
+        // The child is traced already, only its architecture is missing
+        let status = wait::waitpid(pid, None).map_err(|e| Error::ptrace(pid.as_raw(), Phase::Wait, e))?;
+        if !matches!(status, WaitStatus::Stopped(_, Signal::SIGTRAP)) {
+            return Err(Error::TargetExited { pid: pid.as_raw(), status });
+        }
+
+        let (elf_class, arch) = match Self::exe_arch(pid.as_raw()) {
+            Ok(a) => a,
+            Err(e) => {
+                let _ = kill(pid, Signal::SIGKILL);
+                return Err(e);
+            }
+        };
+        log!(verbose, "Launched process {}", pid);
+
+        return Ok(Self::traced(pid, elf_class, arch, verbose));
+    }
+
+    fn exe_arch(pid : i32) -> Result<(u8, Box<dyn Arch>)> {
+        let exefile = open_exe(pid)?;
+        let exepath = format!("/proc/{}/exe", pid);
+        let ehdr = read_ehdr(&exefile).map_err(|e| e.in_file(&exepath))?;
+        let elf_class = ehdr.get_class();
+        let arch = arch::from_elf(ehdr.get_machine(), elf_class).map_err(|e| e.in_file(&exepath))?;
+        return Ok((elf_class, arch));
+    }
+
+    fn traced(pid : Pid, elf_class : u8, arch : Box<dyn Arch>, verbose : bool) -> Self {
+        return RemoteProcess {
+            pid,
+            memory: RemoteMemory::new(pid.as_raw()),
+            elf_class,
+            arch,
+            verbose,
+            attached: true,
+            pending_signals: vec![],
+            threads: vec![],
+            syscall_insn: None,
+            code_region: None
+        };
+    }
+
+    /*
+     * Lets the thread run until it reaches 'addr', with a temporary
+     * breakpoint. Meant for processes that are not running any other
+     * thread yet, such as launched ones
+     */
+    pub fn run_to(&mut self, addr : u64) -> Result<()> {
+        let breakpoint = self.arch.breakpoint();
+        let old_code = self.read_memory(addr, breakpoint.len())?;
+        self.write_memory(addr, breakpoint)?;
+        log!(self.verbose, "Running until {:#x}...", addr);
+
+        let regs = self.getregs()?;
+        let result = self.run_until_trap(regs, false, addr + breakpoint.len() as u64);
+        let restored = self.write_memory(addr, &old_code);
+        result?;
+        restored?;
+
+        let mut regs = self.getregs()?;
+        self.arch.set_ip(&mut regs, addr);
+        return self.setregs(regs);
+    }
+
+    /*
+     * Kills the process, for when it must not run any further
+     */
+    pub fn kill(mut self) -> Result<()> {
+        self.attached = false;
+        kill(self.pid, Signal::SIGKILL).map_err(|e| Error::ptrace(self.pid(), Phase::Detach, e))?;
+        let _ = wait::waitpid(self.pid, None);
+        return Ok(());
+    }
+
+    /*
+     * Stops every other thread of the process, so that none of them
+     * runs while code is patched or a call is in progress. Threads
+     * are seized (following the ones they create) and interrupted,
+     * and '/proc/pid/task' is scanned again until no new thread
+     * shows up. They are resumed when the process is detached.
+     * Threads created by remote calls from then on are let run.
+     * Returns the number of threads stopped
+     */
+    pub fn stop_threads(&mut self) -> Result<usize> {
+        ptrace::setoptions(self.pid, ptrace::Options::PTRACE_O_TRACECLONE)
+            .map_err(|e| Error::ptrace(self.pid(), Phase::Attach, e))?;
+
+        let mut known : Vec<i32> = vec![self.pid()];
+        known.extend(self.threads.iter().map(|t| t.tid.as_raw()));
+        loop {
+            let mut new_tids : Vec<i32> = vec![];
+            enum_tasks(self.pid(), |tid : i32| {
+                if !known.contains(&tid) {
+                    new_tids.push(tid);
+                }
+                return true;
+            })?;
+
+            if new_tids.is_empty() {
+                break;
+            }
+
+            for tid in new_tids {
+                known.push(tid);
+                let tid = Pid::from_raw(tid);
+                match ptrace::seize(tid, ptrace::Options::PTRACE_O_TRACECLONE) {
+                    Ok(_) => {
+                        if let Err(e) = ptrace::interrupt(tid) {
+                            let _ = ptrace::detach(tid, None);
+                            return Err(Error::ptrace(tid.as_raw(), Phase::Interrupt, e));
+                        }
+                    },
+                    // Created by a seized thread, so it is traced already
+                    Err(Errno::EPERM) => (),
+                    Err(Errno::ESRCH) => continue, // exited meanwhile
+                    Err(e) => return Err(Error::ptrace(tid.as_raw(), Phase::Attach, e))
+                }
+
+                if let Some(pending_signal) = self.wait_thread(tid)? {
+                    log!(self.verbose, "Stopped thread {}", tid);
+                    self.threads.push(StoppedThread { tid, pending_signal });
+                }
+            }
+        }
+
+        return Ok(self.threads.len());
+    }
+
+    /*
+     * Waits for a seized thread to stop. Returns None if it exited,
+     * or the signal it stopped for, if it is not a ptrace stop
+     */
+    fn wait_thread(&self, tid : Pid) -> Result<Option<Option<Signal>>> {
+        loop {
+            let status = wait::waitpid(tid, Some(wait::WaitPidFlag::__WALL))
+                .map_err(|e| Error::ptrace(tid.as_raw(), Phase::Wait, e))?;
+
+            match status {
+                WaitStatus::PtraceEvent(..) => return Ok(Some(None)),
+                WaitStatus::Stopped(_, signal) => return Ok(Some(Some(signal))),
+                WaitStatus::Exited(..) | WaitStatus::Signaled(..) => return Ok(None),
+                _ => continue
+            }
+        }
+    }
+
+    pub fn pid(&self) -> i32 {
+        return self.pid.as_raw();
+    }
+
+    pub fn elf_class(&self) -> u8 {
+        return self.elf_class;
+    }
+
+    pub fn arch(&self) -> &dyn Arch {
+        return self.arch.as_ref();
+    }
+
+    /*
+     * Calls the function at 'func_addr' following the SysV calling
+     * convention of the target (x86_64 or i386 cdecl) and returns
+     * the value of rax/eax. Strings and buffers are copied into a
+     * scratch mapping that is freed once the call returns. The
+     * thread state is restored afterwards
+     */
+    pub fn call(&mut self, func_addr : u64, args : &[RemoteArg]) -> Result<u64> {
+        let word_size = self.arch.word_size();
+        let addr_mask = self.arch.addr_mask();
+        if func_addr & !addr_mask != 0 {
+            return Err(Error::InvalidArgument(format!("function address {:#x} is out of the target address space", func_addr)));
+        }
+
+        let mut buffers : Vec<Vec<u8>> = vec![];
+        for arg in args {
+            match arg {
+                RemoteArg::Str(s) => {
+                    let mut data = s.as_bytes().to_vec();
+                    data.push(0); // ensure null terminator
+                    buffers.push(data);
+                },
+                RemoteArg::Bytes(b) => buffers.push(b.to_vec()),
+                _ => ()
+            }
+        }
+
+        let scratch_size = buffers.iter().map(|b| align_up(b.len() as u64, word_size)).sum::<u64>();
+        let scratch = match scratch_size {
+            0 => 0,
+            size => self.alloc(align_up(size, PAGE_SIZE))?
+        };
+
+        let result = self.call_with_scratch(func_addr, args, &buffers, scratch);
+        if scratch != 0 {
+            let freed = self.munmap(scratch, align_up(scratch_size, PAGE_SIZE));
+            let retval = result?;
+            freed?;
+            return Ok(retval);
+        }
+
+        return result;
+    }
+
+    fn call_with_scratch(&mut self, func_addr : u64, args : &[RemoteArg], buffers : &[Vec<u8>], scratch : u64) -> Result<u64> {
+        let addr_mask = self.arch.addr_mask();
+
+        let old_regs = self.getregs()?;
+        log!(self.verbose, "Registers: {}", self.arch.format_regs(&old_regs));
+        let mut regs = old_regs;
+
+        // A thread stopped inside a system call gets it restarted when it
+        // resumes. The hijacked registers must not be subject to that, and
+        // restoring 'old_regs' exactly makes the kernel restart it later
+        if let Some(syscall) = self.arch.interrupted_syscall(&old_regs) {
+            log!(self.verbose, "Stopped in system call {} (result: {}, restart: {})", syscall.nr, syscall.retval, syscall.restarts());
+        }
+        self.arch.clear_syscall(&mut regs);
+
+        let mut buffers = buffers.iter();
+        let mut bufaddr = scratch;
+        let mut values : Vec<u64> = vec![];
+        for arg in args {
+            match arg {
+                RemoteArg::Int(v) | RemoteArg::Ptr(v) => values.push(*v & addr_mask),
+                RemoteArg::Str(_) | RemoteArg::Bytes(_) => {
+                    let data = buffers.next().ok_or(Error::InvalidArgument(String::from("missing argument buffer")))?;
+                    log!(self.verbose, "Argument Buffer (address: {:#x}, size: {}): {}", bufaddr, data.len(), data.bytestr());
+                    self.write_memory(bufaddr, data)?;
+                    values.push(bufaddr);
+                    bufaddr += align_up(data.len() as u64, self.arch.word_size());
+                }
+            }
+        }
+
+        // Arguments that don't fit into registers go on the stack,
+        // past the red zone that leaf functions may be using
+        let (reg_args, stack_args) = values.split_at(values.len().min(self.arch.reg_args()));
+
+        let mut stackbuf : Vec<u8> = vec![];
+        for value in stack_args {
+            stackbuf.extend(self.arch.word_bytes(*value));
+        }
+        let mut sp = self.arch.sp(&old_regs).wrapping_sub(self.arch.red_zone());
+        sp = sp.wrapping_sub(stackbuf.len() as u64) & addr_mask;
+        sp &= !(self.arch.stack_align() - 1);
+        if !stackbuf.is_empty() {
+            log!(self.verbose, "Stack Arguments (address: {:#x}): {}", sp, stackbuf.bytestr());
+            self.write_memory(sp, &stackbuf)?;
+        }
+
+        self.arch.set_sp(&mut regs, sp);
+        self.arch.set_call_target(&mut regs, func_addr);
+        for (index, value) in reg_args.iter().enumerate() {
+            self.arch.set_arg(&mut regs, index, *value);
+        }
+
+        // The stub runs from a private region, so no code that other
+        // threads may be executing is ever modified
+        let stub_addr = self.code_region()?;
+        self.arch.set_ip(&mut regs, stub_addr);
+
+        let stub_end = stub_addr + self.arch.call_stub().len() as u64;
+        let result = self.run_until_trap(regs, false, stub_end);
+        let restored = self.setregs(old_regs);
+
+        let retval = result?;
+        restored?;
+
+        return Ok(retval & addr_mask);
+    }
+
+    /*
+     * Issues a system call in the thread by single-stepping over a
+     * system call instruction that already exists in the target, and
+     * returns its raw result. The thread state is restored afterwards
+     */
+    pub fn syscall(&mut self, sysno : Sysno, args : &[u64]) -> Result<u64> {
+        let insn = self.syscall_insn()?;
+
+        let old_regs = self.getregs()?;
+        let mut regs = old_regs;
+        self.arch.clear_syscall(&mut regs);
+        self.arch.set_syscall(&mut regs, self.arch.syscall_number(sysno), args);
+        self.arch.set_ip(&mut regs, insn);
+        log!(self.verbose, "System call: {:?} {:x?}", sysno, args);
+
+        let result = self.run_until_trap(regs, true, insn + self.arch.syscall_insn().len() as u64);
+        let restored = self.setregs(old_regs);
+
+        let retval = result?;
+        restored?;
+
+        return Ok(retval & self.arch.addr_mask());
+    }
+
+    /*
+     * Maps memory in the target (see mmap(2)). 'offset' is in bytes
+     */
+    pub fn mmap(&mut self, addr : u64, length : u64, prot : ProtFlags, flags : MapFlags, fd : i32, offset : u64) -> Result<u64> {
+        let args = [
+            addr,
+            length,
+            prot.bits() as u64,
+            flags.bits() as u64,
+            fd as i64 as u64 & self.arch.addr_mask(),
+            self.arch.mmap_offset(offset)
+        ];
+        let addr = self.syscall_checked(Sysno::Mmap, &args)?;
+        log!(self.verbose, "Mapped {:#x} bytes at {:#x}", length, addr);
+        return Ok(addr);
+    }
+
+    pub fn munmap(&mut self, addr : u64, length : u64) -> Result<()> {
+        self.syscall_checked(Sysno::Munmap, &[addr, length])?;
+        log!(self.verbose, "Unmapped {:#x} bytes at {:#x}", length, addr);
+        return Ok(());
+    }
+
+    pub fn mprotect(&mut self, addr : u64, length : u64, prot : ProtFlags) -> Result<()> {
+        self.syscall_checked(Sysno::Mprotect, &[addr, length, prot.bits() as u64])?;
+        log!(self.verbose, "Protected {:#x} bytes at {:#x} ({:?})", length, addr, prot);
+        return Ok(());
+    }
+
+    /*
+     * Allocates private read/write memory in the target
+     */
+    pub fn alloc(&mut self, length : u64) -> Result<u64> {
+        return self.mmap(0, length, ProtFlags::PROT_READ | ProtFlags::PROT_WRITE, MapFlags::MAP_PRIVATE | MapFlags::MAP_ANONYMOUS, -1, 0);
+    }
+
+    /*
+     * Creates an anonymous file in the target (memfd_create) holding
+     * 'data', and returns its file descriptor in the target
+     */
+    pub fn memfd(&mut self, name : &str, data : &[u8]) -> Result<i32> {
+        let size = align_up(data.len().min(MEMFD_CHUNK).max(name.len() + 1) as u64, PAGE_SIZE);
+        let scratch = self.alloc(size)?;
+        let result = self.memfd_with_scratch(name, data, scratch);
+        let freed = self.munmap(scratch, size);
+
+        let fd = result?;
+        freed?;
+        return Ok(fd);
+    }
+
+    fn memfd_with_scratch(&mut self, name : &str, data : &[u8], scratch : u64) -> Result<i32> {
+        let mut namebuf = name.as_bytes().to_vec();
+        namebuf.push(0);
+        self.write_memory(scratch, &namebuf)?;
+        let fd = self.syscall_checked(Sysno::MemfdCreate, &[scratch, MFD_CLOEXEC])?;
+        log!(self.verbose, "Created memfd {} ({})", fd, name);
+
+        // The bytes go through the scratch buffer, one chunk at a time
+        let mut written = 0;
+        while written < data.len() {
+            let chunk = &data[written..data.len().min(written + MEMFD_CHUNK)];
+            let count = match self.write_memory(scratch, chunk)
+                .and_then(|_| self.syscall_checked(Sysno::Write, &[fd, scratch, chunk.len() as u64])) {
+                Ok(0) => Err(Error::Syscall { pid: self.pid(), name: String::from(Sysno::Write.name()), errno: Errno::ENOSPC }),
+                result => result
+            };
+
+            match count {
+                Ok(count) => written += count as usize,
+                Err(e) => {
+                    let _ = self.close(fd as i32);
+                    return Err(e);
+                }
+            }
+        }
+        log!(self.verbose, "Wrote {} bytes to memfd {}", written, fd);
+
+        return Ok(fd as i32);
+    }
+
+    pub fn close(&mut self, fd : i32) -> Result<()> {
+        self.syscall_checked(Sysno::Close, &[fd as u64])?;
+        return Ok(());
+    }
+
+    /*
+     * Like 'syscall', but turns a negative result into an error
+     */
+    fn syscall_checked(&mut self, sysno : Sysno, args : &[u64]) -> Result<u64> {
+        let retval = self.syscall(sysno, args)?;
+        let shift = 64 - self.arch.word_size() * 8;
+        let errno = -(((retval << shift) as i64) >> shift); // sign extend
+        if (1..4096).contains(&errno) {
+            return Err(Error::Syscall {
+                pid: self.pid(),
+                name: String::from(sysno.name()),
+                errno: Errno::from_i32(errno as i32)
+            });
+        }
+
+        return Ok(retval);
+    }
+
+    /*
+     * Finds a system call instruction in the target. The one right
+     * before the instruction pointer is used if the thread is in a
+     * system call, otherwise the executable mappings are scanned
+     */
+    fn syscall_insn(&mut self) -> Result<u64> {
+        if let Some(addr) = self.syscall_insn {
+            return Ok(addr);
+        }
+
+        let insn = self.arch.syscall_insn();
+        let regs = self.getregs()?;
+        let mut found : Option<u64> = None;
+        if self.arch.syscall_nr(&regs).is_some() {
+            let addr = self.arch.ip(&regs) - insn.len() as u64;
+            if self.read_memory(addr, insn.len()).map(|code| code == insn).unwrap_or(false) {
+                found = Some(addr);
+            }
+        }
+
+        if found.is_none() {
+            let mut regions : Vec<MapsEntry> = vec![];
+            enum_maps(self.pid(), |entry : MapsEntry| {
+                if entry.is_file() && entry.flags.contains(ProtFlags::PROT_EXEC) {
+                    regions.push(entry);
+                }
+                return true;
+            })?;
+
+            'regions: for region in regions {
+                let mut addr = region.base;
+                while addr < region.end {
+                    // Chunks overlap so an instruction is not split between them
+                    let size = SCAN_CHUNK.min(region.end - addr);
+                    let code = match self.read_memory(addr, size as usize) {
+                        Ok(c) => c,
+                        Err(_) => continue 'regions
+                    };
+                    if let Some(offset) = code.windows(insn.len()).position(|w| w == insn) {
+                        found = Some(addr + offset as u64);
+                        break 'regions;
+                    }
+                    addr += size - (insn.len() as u64 - 1).min(size - 1);
+                }
+            }
+        }
+
+        let addr = found.ok_or(Error::NoSyscallInstruction(self.pid()))?;
+        log!(self.verbose, "System call instruction: {:#x}", addr);
+        self.syscall_insn = Some(addr);
+        return Ok(addr);
+    }
+
+    /*
+     * Maps (once) a private executable page holding the call stub
+     */
+    fn code_region(&mut self) -> Result<u64> {
+        if let Some(addr) = self.code_region {
+            return Ok(addr);
+        }
+
+        let prot = ProtFlags::PROT_READ | ProtFlags::PROT_EXEC;
+        let addr = self.mmap(0, CODE_REGION_SIZE, prot, MapFlags::MAP_PRIVATE | MapFlags::MAP_ANONYMOUS, -1, 0)?;
+
+        let stub = self.arch.call_stub();
+        log!(self.verbose, "Payload Buffer (address: {:#x}, size: {}): {}", addr, stub.len(), stub.to_vec().bytestr());
+        self.code_region = Some(addr);
+        self.write_memory(addr, stub)?;
+        return Ok(addr);
+    }
+
+    /*
+     * Runs the thread with 'regs' until it traps at 'trap_ip', either
+     * after the breakpoint at the end of the call stub or after a
+     * single step, and returns the return value register. A fault in
+     * the called code, or a single step that lands elsewhere, fails
+     * the call; the caller is responsible for restoring the thread
+     * state. Other signals, including traps of the called code, are
+     * postponed until the process is detached, and the thread is
+     * resumed (or the step issued again)
+     */
+    fn run_until_trap(&mut self, regs : Regs, step : bool, trap_ip : u64) -> Result<u64> {
+        self.setregs(regs)?;
+        log!(self.verbose, "Set registers on target process");
+
+        loop {
+            let resumed = if step {
+                ptrace::step(self.pid, None)
+            } else {
+                ptrace::cont(self.pid, None)
+            };
+            resumed.map_err(|e| Error::ptrace(self.pid(), Phase::Continue, e))?;
+            log!(self.verbose, "Waiting for target process to stop...");
+
+            let signal = match self.wait_stopped()? {
+                WaitStatus::PtraceEvent(_, _, event) if event == ptrace::Event::PTRACE_EVENT_CLONE as i32 => {
+                    self.release_cloned_thread()?;
+                    continue;
+                },
+                // Interrupt or group stop, nothing to deliver
+                WaitStatus::PtraceEvent(..) => continue,
+                WaitStatus::Stopped(_, signal) => signal,
+                _ => continue
+            };
+
+            let ip = self.arch.ip(&self.getregs()?);
+            if signal == Signal::SIGTRAP && ip == trap_ip {
+                break;
+            }
+
+            let siginfo = ptrace::getsiginfo(self.pid).map_err(|e| Error::ptrace(self.pid(), Phase::GetRegs, e))?;
+            if is_fault(signal, &siginfo) {
+                let addr = unsafe { siginfo.si_addr() as u64 };
+                log!(self.verbose, "Remote call stopped by {} at {:#x}", signal, ip);
+                return Err(Error::Fault { pid: self.pid(), signal, addr, ip });
+            }
+            if step && signal == Signal::SIGTRAP && siginfo.si_code > 0 {
+                return Err(Error::UnexpectedTrap { pid: self.pid(), ip, expected: trap_ip });
+            }
+
+            // Deliver it once the thread state is restored
+            log!(self.verbose, "Postponing signal: {}", signal);
+            self.pending_signals.push(signal);
+        }
+        log!(self.verbose, "Target process stopped");
+
+        let post_regs = self.getregs()?;
+        log!(self.verbose, "Post call registers: {}", self.arch.format_regs(&post_regs));
+        return Ok(self.arch.retval(&post_regs));
+    }
+
+    /*
+     * Lets the thread just created by the called code run: it is
+     * traced because the process follows clones once its threads
+     * are stopped, but the call may well be waiting for it
+     */
+    fn release_cloned_thread(&mut self) -> Result<()> {
+        let tid = ptrace::getevent(self.pid).map_err(|e| Error::ptrace(self.pid(), Phase::Wait, e))?;
+        let tid = Pid::from_raw(tid as i32);
+
+        if let Some(pending_signal) = self.wait_thread(tid)? {
+            match ptrace::detach(tid, pending_signal) {
+                Ok(_) | Err(Errno::ESRCH) => log!(self.verbose, "Released new thread {}", tid),
+                Err(e) => return Err(Error::ptrace(tid.as_raw(), Phase::Detach, e))
+            }
+        }
+        return Ok(());
+    }
+
+    pub fn getregs(&self) -> Result<Regs> {
+        return ptrace::getregs(self.pid).map_err(|e| Error::ptrace(self.pid(), Phase::GetRegs, e));
+    }
+
+    pub fn setregs(&self, regs : Regs) -> Result<()> {
+        return ptrace::setregs(self.pid, regs).map_err(|e| Error::ptrace(self.pid(), Phase::SetRegs, e));
+    }
+
+    pub fn memory(&self) -> &RemoteMemory {
+        return &self.memory;
+    }
+
+    pub fn read_memory(&self, addr : u64, size : usize) -> Result<Vec<u8>> {
+        return self.memory.read(addr, size);
+    }
+
+    pub fn write_memory(&self, addr : u64, buf : &[u8]) -> Result<()> {
+        return self.memory.write(addr, buf);
+    }
+
+    pub fn detach(mut self) -> Result<()> {
+        return self.detach_inner();
+    }
+
+    fn detach_inner(&mut self) -> Result<()> {
+        if !self.attached {
+            return Ok(());
+        }
+
+        // Best effort: the process may be gone already
+        if let Some(addr) = self.code_region.take() {
+            if let Err(e) = self.munmap(addr, CODE_REGION_SIZE) {
+                log!(self.verbose, "Unable to unmap the code region: {}", e);
+            }
+        }
+
+        self.attached = false;
+        let mut result = Ok(());
+        for thread in self.threads.drain(..) {
+            match ptrace::detach(thread.tid, thread.pending_signal) {
+                Ok(_) | Err(Errno::ESRCH) => log!(self.verbose, "Resumed thread {}", thread.tid),
+                Err(e) => result = result.and(Err(Error::ptrace(thread.tid.as_raw(), Phase::Detach, e)))
+            }
+        }
+
+        // Only one signal can be injected on detach, the others are sent
+        // again and stay pending until the process runs
+        let mut signals = self.pending_signals.drain(..);
+        let first_signal = signals.next();
+        for signal in signals {
+            if let Err(e) = kill(self.pid, signal) {
+                log!(self.verbose, "Unable to deliver {}: {}", signal, e);
+            }
+        }
+        ptrace::detach(self.pid, first_signal).map_err(|e| Error::ptrace(self.pid(), Phase::Detach, e))?;
+        log!(self.verbose, "Detached from process {}", self.pid);
+        return result;
+    }
+
+    /*
+     * Waits for the traced process to enter a stopped state,
+     * failing if it exited or was killed instead
+     */
+    fn wait_stopped(&mut self) -> Result<WaitStatus> {
+        let status = wait::waitpid(self.pid, Some(wait::WaitPidFlag::WSTOPPED))
+            .map_err(|e| Error::ptrace(self.pid(), Phase::Wait, e))?;
+
+        return match status {
+            WaitStatus::Stopped(..) | WaitStatus::PtraceEvent(..) => Ok(status),
+            _ => {
+                self.attached = false;
+                Err(Error::TargetExited { pid: self.pid(), status })
+            }
+        };
+    }
+}
+
+impl Drop for RemoteProcess {
+    fn drop(&mut self) {
+        let _ = self.detach_inner();
+    }
+}
+
+/*
+ * Whether a stop signal is a fault raised by the kernel for the
+ * instruction being executed, rather than one sent by a process
+ */
+fn is_fault(signal : Signal, siginfo : &libc::siginfo_t) -> bool {
+    let synchronous = matches!(signal, Signal::SIGSEGV | Signal::SIGBUS | Signal::SIGILL | Signal::SIGFPE);
+    return synchronous && siginfo.si_code > 0;
+}
+
+pub(crate) fn align_up(value : u64, align : u64) -> u64 {
+    return (value + align - 1) & !(align - 1);
+}
+
+pub(crate) trait ByteFmt {
+    fn bytestr(&self) -> String;
+}
+
+impl ByteFmt for Vec<u8> {
+    fn bytestr(&self) -> String {
+        let mut output = String::new();
+        for byte in self {
+            output = format!("{}\\x{:02x}", output, byte);
+        }
+        output = format!("\"{}\"", output);
+        return output;
+    }
+}